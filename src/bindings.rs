@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::env;
+use gesture::GestureEvent;
+use geom::Dir;
+use document::Layer;
+
+// The part of a gesture event that can be bound to a command.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Gesture {
+    Tap(usize),
+    Hold,
+    LongHold,
+    Swipe(Dir, usize),
+    Pinch,
+    Spread,
+    Rotate,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    Undo,
+    Redo,
+    Save,
+    Clear,
+    Invert,
+    ToggleEraser,
+    CyclePenColor,
+    NextPage,
+    PreviousPage,
+    InsertPage,
+    DeletePage,
+    // Switch between the ink and the annotation layers.
+    SwitchLayer,
+    ToggleLayer(Layer),
+    ClearLayer,
+    // Remove the background image and the strokes of the background layer.
+    ClearBackground,
+    Calibrate,
+}
+
+impl Gesture {
+    pub fn from_event(evt: &GestureEvent) -> Option<Gesture> {
+        match *evt {
+            GestureEvent::Tap { fingers_count, .. } => Some(Gesture::Tap(fingers_count)),
+            GestureEvent::Hold { long: false, .. } => Some(Gesture::Hold),
+            GestureEvent::Hold { long: true, .. } => Some(Gesture::LongHold),
+            GestureEvent::Swipe { dir, fingers_count, .. } => Some(Gesture::Swipe(dir, fingers_count)),
+            GestureEvent::Pinch { .. } => Some(Gesture::Pinch),
+            GestureEvent::Spread { .. } => Some(Gesture::Spread),
+            GestureEvent::Rotate { .. } => Some(Gesture::Rotate),
+            GestureEvent::Relay(..) => None,
+        }
+    }
+
+    // The number of fingers that were lifted to produce the gesture.
+    pub fn fingers_count(&self) -> usize {
+        match *self {
+            Gesture::Tap(n) | Gesture::Swipe(_, n) => n,
+            Gesture::Pinch | Gesture::Spread | Gesture::Rotate => 2,
+            Gesture::Hold | Gesture::LongHold => 0,
+        }
+    }
+
+    // Parses names such as `tap-2`, `long-hold` or `swipe-west-2`.
+    pub fn parse(name: &str) -> Option<Gesture> {
+        let parts: Vec<&str> = name.split('-').collect();
+        match parts.as_slice() {
+            ["tap", n] => n.parse().ok().map(Gesture::Tap),
+            ["hold"] => Some(Gesture::Hold),
+            ["long", "hold"] => Some(Gesture::LongHold),
+            ["swipe", dir, n] => {
+                let dir = match *dir {
+                    "north" => Dir::North,
+                    "east" => Dir::East,
+                    "south" => Dir::South,
+                    "west" => Dir::West,
+                    _ => return None,
+                };
+                n.parse().ok().map(|n| Gesture::Swipe(dir, n))
+            },
+            ["pinch"] => Some(Gesture::Pinch),
+            ["spread"] => Some(Gesture::Spread),
+            ["rotate"] => Some(Gesture::Rotate),
+            _ => None,
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(name: &str) -> Option<Command> {
+        match name {
+            "undo" => Some(Command::Undo),
+            "redo" => Some(Command::Redo),
+            "save" => Some(Command::Save),
+            "clear" => Some(Command::Clear),
+            "invert" => Some(Command::Invert),
+            "toggle-eraser" => Some(Command::ToggleEraser),
+            "cycle-pen-color" => Some(Command::CyclePenColor),
+            "next-page" => Some(Command::NextPage),
+            "previous-page" => Some(Command::PreviousPage),
+            "insert-page" => Some(Command::InsertPage),
+            "delete-page" => Some(Command::DeletePage),
+            "switch-layer" => Some(Command::SwitchLayer),
+            "toggle-background" => Some(Command::ToggleLayer(Layer::Background)),
+            "toggle-ink" => Some(Command::ToggleLayer(Layer::Ink)),
+            "toggle-annotation" => Some(Command::ToggleLayer(Layer::Annotation)),
+            "clear-layer" => Some(Command::ClearLayer),
+            "clear-background" => Some(Command::ClearBackground),
+            "calibrate" => Some(Command::Calibrate),
+            _ => None,
+        }
+    }
+}
+
+// The default bindings, overridden by the space separated `GESTURE:COMMAND`
+// pairs of `SKETCH_BINDINGS`. The special command `none` removes a binding.
+pub fn bindings() -> HashMap<Gesture, Command> {
+    let mut bindings = HashMap::new();
+    bindings.insert(Gesture::Tap(2), Command::Undo);
+    bindings.insert(Gesture::Tap(3), Command::Redo);
+    bindings.insert(Gesture::Swipe(Dir::North, 2), Command::ToggleEraser);
+    bindings.insert(Gesture::Swipe(Dir::South, 2), Command::CyclePenColor);
+    bindings.insert(Gesture::Swipe(Dir::West, 2), Command::NextPage);
+    bindings.insert(Gesture::Swipe(Dir::East, 2), Command::PreviousPage);
+    bindings.insert(Gesture::Spread, Command::InsertPage);
+    bindings.insert(Gesture::Swipe(Dir::North, 3), Command::SwitchLayer);
+    if let Ok(value) = env::var("SKETCH_BINDINGS") {
+        for pair in value.split_whitespace() {
+            let mut names = pair.splitn(2, ':');
+            if let (Some(gesture), Some(command)) = (names.next().and_then(Gesture::parse),
+                                                     names.next()) {
+                if let Some(command) = Command::parse(command) {
+                    bindings.insert(gesture, command);
+                } else if command == "none" {
+                    bindings.remove(&gesture);
+                }
+            }
+        }
+    }
+    bindings
+}
@@ -0,0 +1,149 @@
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::env;
+use input::AbsInfo;
+use geom::Point;
+
+const CALIBRATION_NAME: &str = "calibration.txt";
+
+// The calibration is stored next to the executable, whatever the working directory is.
+pub fn calibration_path() -> PathBuf {
+    env::current_exe().ok()
+                      .and_then(|exe| exe.parent().map(|dir| dir.join(CALIBRATION_NAME)))
+                      .unwrap_or_else(|| PathBuf::from(CALIBRATION_NAME))
+}
+
+// Affine transform from raw touch coordinates to screen coordinates:
+// x' = a * x + b * y + c
+// y' = d * x + e * y + f
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+    pub e: f32,
+    pub f: f32,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            a: 1.0, b: 0.0, c: 0.0,
+            d: 0.0, e: 1.0, f: 0.0,
+        }
+    }
+
+    // The transform that fits the tested devices: the axes are swapped, the x axis is mirrored
+    // and the raw values are scaled from the axis ranges to the screen dimensions.
+    pub fn from_ranges(x_range: Option<&AbsInfo>, y_range: Option<&AbsInfo>, dims: (u32, u32)) -> Transform {
+        let (x_min, x_scale) = x_range.map_or((0.0, 1.0), |info| {
+            (info.minimum as f32, (dims.1 - 1) as f32 / (info.maximum - info.minimum) as f32)
+        });
+        let (y_min, y_scale) = y_range.map_or((0.0, 1.0), |info| {
+            (info.minimum as f32, (dims.0 - 1) as f32 / (info.maximum - info.minimum) as f32)
+        });
+        Transform {
+            a: 0.0, b: -y_scale, c: (dims.0 - 1) as f32 + y_min * y_scale,
+            d: x_scale, e: 0.0, f: -x_min * x_scale,
+        }
+    }
+
+    // Least squares fit of the transform that maps each `raw` point to its `screen` point.
+    // Returns `None` if the raw points are aligned.
+    pub fn fit(samples: &[(Point, Point)]) -> Option<Transform> {
+        let mut m = [[0.0f64; 3]; 3];
+        let mut vx = [0.0f64; 3];
+        let mut vy = [0.0f64; 3];
+        for &(raw, screen) in samples {
+            let row = [raw.x as f64, raw.y as f64, 1.0];
+            for i in 0..3 {
+                for j in 0..3 {
+                    m[i][j] += row[i] * row[j];
+                }
+                vx[i] += row[i] * screen.x as f64;
+                vy[i] += row[i] * screen.y as f64;
+            }
+        }
+        let det = det3(&m);
+        if det.abs() < 1e-9 {
+            return None;
+        }
+        let solve = |v: &[f64; 3]| -> [f32; 3] {
+            let mut sol = [0.0f32; 3];
+            for (k, s) in sol.iter_mut().enumerate() {
+                let mut mk = m;
+                for i in 0..3 {
+                    mk[i][k] = v[i];
+                }
+                *s = (det3(&mk) / det) as f32;
+            }
+            sol
+        };
+        let [a, b, c] = solve(&vx);
+        let [d, e, f] = solve(&vy);
+        Some(Transform {
+            a, b, c,
+            d, e, f,
+        })
+    }
+
+    pub fn apply(&self, raw: &Point) -> Point {
+        let (x, y): (f32, f32) = (*raw).into();
+        Point::new((self.a * x + self.b * y + self.c).round() as i32,
+                   (self.d * x + self.e * y + self.f).round() as i32)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Transform> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        let coefs: Vec<f32> = text.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+        if coefs.len() != 6 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected six coefficients"));
+        }
+        Ok(Transform {
+            a: coefs[0], b: coefs[1], c: coefs[2],
+            d: coefs[3], e: coefs[4], f: coefs[5],
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "{} {} {} {} {} {}", self.a, self.b, self.c, self.d, self.e, self.f)
+    }
+}
+
+fn det3(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+    m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+    m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+#[cfg(test)]
+mod tests {
+    use input::AbsInfo;
+    use super::Transform;
+
+    #[test]
+    fn fit_recovers_the_transform_of_the_taps() {
+        let x_range = AbsInfo { minimum: 0, maximum: 1023, .. AbsInfo::default() };
+        let y_range = AbsInfo { minimum: 0, maximum: 767, .. AbsInfo::default() };
+        let expected = Transform::from_ranges(Some(&x_range), Some(&y_range), (600, 800));
+        let raws = [pt!(100, 80), pt!(100, 700), pt!(900, 700), pt!(900, 80), pt!(512, 384)];
+        let samples: Vec<_> = raws.iter().map(|raw| (*raw, expected.apply(raw))).collect();
+        let transform = Transform::fit(&samples).unwrap();
+        for raw in &raws {
+            assert_eq!(transform.apply(raw), expected.apply(raw));
+        }
+        // The screen points are rounded.
+        let origin = transform.apply(&pt!(0, 0));
+        assert!((origin.x - 599).abs() <= 1 && origin.y.abs() <= 1);
+    }
+
+    #[test]
+    fn fit_rejects_aligned_taps() {
+        let samples: Vec<_> = (0..5).map(|i| (pt!(100 * i, 50 * i), pt!(10 * i, 20 * i))).collect();
+        assert!(Transform::fit(&samples).is_none());
+    }
+}
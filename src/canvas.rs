@@ -0,0 +1,143 @@
+use std::path::Path;
+use std::io;
+use display::{Display, Mode};
+use geom::{Point, Rectangle};
+
+// An offscreen, partially transparent, grayscale image of a region of a display.
+pub struct Canvas {
+    rect: Rectangle,
+    gray: Vec<u8>,
+    alpha: Vec<f32>,
+    // Whether drawing removes the pixels instead of painting them.
+    pub erasing: bool,
+}
+
+impl Canvas {
+    // A transparent canvas.
+    pub fn new(rect: Rectangle) -> Canvas {
+        let len = (rect.width() * rect.height()) as usize;
+        Canvas {
+            rect,
+            gray: vec![0xff; len],
+            alpha: vec![0.0; len],
+            erasing: false,
+        }
+    }
+
+    fn index(&self, x: u32, y: u32) -> Option<usize> {
+        let pt = Point::new(x as i32, y as i32);
+        if self.rect.contains(&pt) {
+            Some(((pt.y - self.rect.min.y) * self.rect.width() as i32 + pt.x - self.rect.min.x) as usize)
+        } else {
+            None
+        }
+    }
+
+    // Draw `other` over this canvas.
+    pub fn composite(&mut self, other: &Canvas) {
+        if other.rect == self.rect {
+            for index in 0..self.alpha.len() {
+                if other.alpha[index] > 0.0 {
+                    self.paint_index(index, other.gray[index], other.alpha[index]);
+                }
+            }
+            return;
+        }
+        for y in self.rect.min.y..self.rect.max.y {
+            for x in self.rect.min.x..self.rect.max.x {
+                if let Some(index) = other.index(x as u32, y as u32) {
+                    if other.alpha[index] > 0.0 {
+                        self.paint(x as u32, y as u32, other.gray[index], other.alpha[index]);
+                    }
+                }
+            }
+        }
+    }
+
+    // Copy the canvas, as if it was on a white background, to the display.
+    // Consecutive pixels of the same gray level are drawn as a single span.
+    pub fn draw<D: Display>(&self, display: &mut D) {
+        for y in self.rect.min.y..self.rect.max.y {
+            let mut start = self.rect.min.x;
+            let mut current = None;
+            for x in self.rect.min.x..self.rect.max.x {
+                let index = self.index(x as u32, y as u32).unwrap();
+                let gray = (255.0 + (self.gray[index] as f32 - 255.0) * self.alpha[index]).round() as u8;
+                if current != Some(gray) {
+                    if let Some(previous) = current {
+                        display.draw_span(y as u32, start as u32, x as u32, previous);
+                    }
+                    start = x;
+                    current = Some(gray);
+                }
+            }
+            if let Some(gray) = current {
+                display.draw_span(y as u32, start as u32, self.rect.max.x as u32, gray);
+            }
+        }
+    }
+
+    fn paint(&mut self, x: u32, y: u32, gray: u8, alpha: f32) {
+        if let Some(index) = self.index(x, y) {
+            self.paint_index(index, gray, alpha);
+        }
+    }
+
+    fn paint_index(&mut self, index: usize, gray: u8, alpha: f32) {
+        let below = self.alpha[index] * (1.0 - alpha);
+        let total = alpha + below;
+        if total > 0.0 {
+            let mixed = (gray as f32 * alpha + self.gray[index] as f32 * below) / total;
+            self.gray[index] = mixed.round() as u8;
+        }
+        self.alpha[index] = total;
+    }
+
+    fn erase(&mut self, x: u32, y: u32, alpha: f32) {
+        if let Some(index) = self.index(x, y) {
+            self.alpha[index] *= 1.0 - alpha;
+        }
+    }
+}
+
+impl Display for Canvas {
+    fn set_pixel(&mut self, x: u32, y: u32, gray: u8) {
+        self.blend_pixel(x, y, gray, 1.0);
+    }
+
+    fn get_pixel(&self, x: u32, y: u32) -> u8 {
+        self.index(x, y).map_or(0xff, |index| self.gray[index])
+    }
+
+    fn blend_pixel(&mut self, x: u32, y: u32, gray: u8, alpha: f32) {
+        if self.erasing {
+            self.erase(x, y, alpha);
+        } else {
+            self.paint(x, y, gray, alpha);
+        }
+    }
+
+    // A canvas is never shown.
+    fn update(&mut self, _: Rectangle, _: Mode) -> io::Result<u32> {
+        Ok(0)
+    }
+
+    fn wait(&mut self, _: u32) -> io::Result<i32> {
+        Ok(0)
+    }
+
+    fn dims(&self) -> (u32, u32) {
+        (self.rect.max.x as u32, self.rect.max.y as u32)
+    }
+
+    fn toggle_inverse(&mut self) {
+    }
+
+    fn save(&self, _: &Path) -> io::Result<()> {
+        Err(io::Error::other("a canvas can't be saved"))
+    }
+
+    fn is_color(&self) -> bool {
+        false
+    }
+}
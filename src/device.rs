@@ -15,6 +15,9 @@ pub enum Model {
     Touch2,
     AuraONE,
     AuraEdition2,
+    AuraH2OEdition2,
+    ClaraHD,
+    Forma,
 }
 
 #[derive(Debug)]
@@ -55,34 +58,49 @@ impl Device {
             },
             "phoenix" => Device {
                 model: Model::Aura,
-                proto: TouchProto::Multi,
+                proto: TouchProto::MultiA,
                 dpi: 212,
             },
             "dahlia" => Device {
                 model: Model::AuraH2O,
-                proto: TouchProto::Multi,
+                proto: TouchProto::MultiA,
                 dpi: 265,
             },
             "alyssum" => Device {
                 model: Model::GloHD,
-                proto: TouchProto::Multi,
+                proto: TouchProto::MultiA,
                 dpi: 300,
             },
             "pika" => Device {
                 model: Model::Touch2,
-                proto: TouchProto::Multi,
+                proto: TouchProto::MultiA,
                 dpi: 167,
             },
             "daylight" => Device {
                 model: Model::AuraONE,
-                proto: TouchProto::Multi,
+                proto: TouchProto::MultiA,
                 dpi: 300,
             },
             "star" => Device {
                 model: Model::AuraEdition2,
-                proto: TouchProto::Multi,
+                proto: TouchProto::MultiA,
                 dpi: 212,
             },
+            "snow" => Device {
+                model: Model::AuraH2OEdition2,
+                proto: TouchProto::MultiB,
+                dpi: 265,
+            },
+            "nova" => Device {
+                model: Model::ClaraHD,
+                proto: TouchProto::MultiB,
+                dpi: 300,
+            },
+            "frost" => Device {
+                model: Model::Forma,
+                proto: TouchProto::MultiB,
+                dpi: 300,
+            },
             _ => Device::default(),
         }
     }
@@ -0,0 +1,382 @@
+use std::cmp;
+use std::mem::swap;
+use std::path::Path;
+use std::io;
+use geom::{Point, Rectangle};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    Fast,
+    Partial,
+    Gui,
+    Full,
+}
+
+// The methods that an adaptor, which draws on the display held in its `display` field, passes through.
+macro_rules! forward_display {
+    () => {
+        fn get_pixel(&self, x: u32, y: u32) -> u8 {
+            self.display.get_pixel(x, y)
+        }
+
+        fn update(&mut self, rect: Rectangle, mode: Mode) -> io::Result<u32> {
+            self.display.update(rect, mode)
+        }
+
+        fn wait(&mut self, token: u32) -> io::Result<i32> {
+            self.display.wait(token)
+        }
+
+        fn dims(&self) -> (u32, u32) {
+            self.display.dims()
+        }
+
+        fn toggle_inverse(&mut self) {
+            self.display.toggle_inverse();
+        }
+
+        fn save(&self, path: &Path) -> io::Result<()> {
+            self.display.save(path)
+        }
+
+        fn is_color(&self) -> bool {
+            self.display.is_color()
+        }
+    };
+}
+
+// Everything sketch needs from a screen.
+// Only `set_pixel` and the refresh related methods are backend specific,
+// the drawing primitives are built on top of `set_pixel` and `draw_span`,
+// which backends can override with faster versions.
+pub trait Display {
+    fn set_pixel(&mut self, x: u32, y: u32, gray: u8);
+
+    fn get_pixel(&self, x: u32, y: u32) -> u8;
+
+    // Tell the backend that the given rectangle needs to be redrawn.
+    // Returns a token that can be passed to `wait`.
+    fn update(&mut self, rect: Rectangle, mode: Mode) -> io::Result<u32>;
+
+    // Wait for a specific update to complete
+    fn wait(&mut self, token: u32) -> io::Result<i32>;
+
+    fn dims(&self) -> (u32, u32);
+
+    fn toggle_inverse(&mut self);
+
+    fn save(&self, path: &Path) -> io::Result<()>;
+
+    // Whether the saved images are RGB images.
+    fn is_color(&self) -> bool;
+
+    // Mix `gray` with the current gray level of the pixel, `alpha` is the weight of `gray`.
+    fn blend_pixel(&mut self, x: u32, y: u32, gray: u8, alpha: f32) {
+        let current = self.get_pixel(x, y) as f32;
+        let mixed = current + (gray as f32 - current) * alpha;
+        self.set_pixel(x, y, mixed.round() as u8);
+    }
+
+    // Horizontal run of pixels of row `y`, from `x_min` (included) to `x_max` (excluded).
+    fn draw_span(&mut self, y: u32, x_min: u32, x_max: u32, gray: u8) {
+        for x in x_min..x_max {
+            self.set_pixel(x, y, gray);
+        }
+    }
+
+    fn fill_rect(&mut self, rect: &Rectangle, gray: u8) {
+        let (width, height) = self.dims();
+        let x_min = cmp::max(0, rect.min.x) as u32;
+        let x_max = cmp::min(width as i32, rect.max.x);
+        if x_max <= x_min as i32 {
+            return;
+        }
+        for y in cmp::max(0, rect.min.y)..cmp::min(height as i32, rect.max.y) {
+            self.draw_span(y as u32, x_min, x_max as u32, gray);
+        }
+    }
+
+    fn draw_disk(&mut self, center: &Point, radius: u32, gray: u8) -> Rectangle {
+        let (width, height) = self.dims();
+        let x_min = cmp::max(0, center.x - radius as i32);
+        let x_max = cmp::min(width as i32, center.x + radius as i32 + 1);
+        let y_min = cmp::max(0, center.y - radius as i32);
+        let y_max = cmp::min(height as i32, center.y + radius as i32 + 1);
+        let r2 = radius.pow(2) as i32;
+        for y in y_min..y_max {
+            // Half width of the row: the largest `dx` such that `dx² + dy² ≤ r²`.
+            let rem = r2 - (y - center.y).pow(2);
+            let mut dx = (rem as f32).sqrt() as i32;
+            while dx * dx > rem {
+                dx -= 1;
+            }
+            while (dx + 1) * (dx + 1) <= rem {
+                dx += 1;
+            }
+            let start = cmp::max(x_min, center.x - dx);
+            let end = cmp::min(x_max, center.x + dx + 1);
+            if start < end {
+                self.draw_span(y as u32, start as u32, end as u32, gray);
+            }
+        }
+        rect!(x_min, y_min, x_max, y_max)
+    }
+
+    // Segment with round caps: the body is filled with the pixels within the radius,
+    // interpolated between `start_radius` and `end_radius`, of the segment,
+    // and the caps are disks centered on the end points.
+    fn draw_thick_segment(&mut self, start: &Point, end: &Point, start_radius: u32, end_radius: u32, gray: u8) -> Rectangle {
+        let mut rect = self.draw_disk(start, start_radius, gray);
+        rect.absorb(&self.draw_disk(end, end_radius, gray));
+        let d = *end - *start;
+        let len2 = d.x * d.x + d.y * d.y;
+        if len2 == 0 {
+            return rect;
+        }
+        let (x_min, y_min) = (rect.min.x, rect.min.y);
+        let (x_max, y_max) = (rect.max.x, rect.max.y);
+        let (r0, r1) = (start_radius as f32, end_radius as f32);
+        let inside = |x: i32, y: i32| {
+            let v = Point::new(x, y) - *start;
+            let dot = v.x * d.x + v.y * d.y;
+            if dot < 0 || dot > len2 {
+                return false;
+            }
+            let t = dot as f32 / len2 as f32;
+            let radius = r0 + (r1 - r0) * t;
+            let cross = (v.x * d.y - v.y * d.x) as f32;
+            cross * cross <= radius * radius * len2 as f32
+        };
+        // The body is convex: its pixels form a single span on each row.
+        for y in y_min..y_max {
+            if let Some(start) = (x_min..x_max).find(|&x| inside(x, y)) {
+                let end = (start..x_max).rev().find(|&x| inside(x, y)).unwrap_or(start) + 1;
+                self.draw_span(y as u32, start as u32, end as u32, gray);
+            }
+        }
+        rect
+    }
+
+    // Bresenham's line algorithm
+    fn draw_line_segment(&mut self, start: &Point, end: &Point, gray: u8) {
+        let (mut x0, mut y0) = (start.x, start.y);
+        let (mut x1, mut y1) = (end.x, end.y);
+
+        let is_steep = (y1 - y0).abs() > (x1 - x0).abs();
+
+        if is_steep {
+            swap(&mut x0, &mut y0);
+            swap(&mut x1, &mut y1);
+        }
+
+        if x0 > x1 {
+            swap(&mut x0, &mut x1);
+            swap(&mut y0, &mut y1);
+        }
+
+        let dx = x1 - x0;
+        let dy = (y1 - y0).abs();
+        let mut error = dx / 2;
+
+        let y_step = (y1 - y0).signum();
+        let mut y = y0;
+
+        for x in x0..(x1 + 1) {
+            if is_steep {
+                self.set_pixel(y as u32, x as u32, gray);
+            } else {
+                self.set_pixel(x as u32, y as u32, gray);
+            }
+            error -= dy;
+            if error < 0 {
+                y = y + y_step;
+                error += dx;
+            }
+        }
+    }
+}
+
+// Fraction of each pixel of a rectangle covered by a shape.
+// The shapes are accumulated before being drawn, so that the pixels shared
+// by overlapping shapes are only blended once.
+pub struct Coverage {
+    rect: Rectangle,
+    values: Vec<f32>,
+}
+
+impl Coverage {
+    pub fn new(rect: Rectangle) -> Coverage {
+        Coverage {
+            rect,
+            values: vec![0.0; (rect.width() * rect.height()) as usize],
+        }
+    }
+
+    // A segment whose end points are equal is a disk.
+    // The coverage of a pixel is derived from its distance to the shape:
+    // pixels whose centers lie half a pixel inside the shape are fully covered.
+    pub fn add_thick_segment(&mut self, start: &Point, end: &Point, start_radius: f32, end_radius: f32) {
+        let reach = start_radius.max(end_radius).ceil() as i32 + 1;
+        let mut bounds = Rectangle::from_point(start);
+        bounds.merge(end);
+        let bounds = rect!(bounds.min - reach, bounds.max + reach);
+        let rect = match self.rect.intersection(&bounds) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let (x0, y0) = (start.x as f32, start.y as f32);
+        let (dx, dy) = ((end.x - start.x) as f32, (end.y - start.y) as f32);
+        let len2 = dx * dx + dy * dy;
+        let width = self.rect.width() as i32;
+        for y in rect.min.y..rect.max.y {
+            for x in rect.min.x..rect.max.x {
+                let (vx, vy) = (x as f32 - x0, y as f32 - y0);
+                let t = if len2 > 0.0 {
+                    ((vx * dx + vy * dy) / len2).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                let (ex, ey) = (vx - t * dx, vy - t * dy);
+                let radius = start_radius + (end_radius - start_radius) * t;
+                let value = (radius + 0.5 - (ex * ex + ey * ey).sqrt()).clamp(0.0, 1.0);
+                let index = ((y - self.rect.min.y) * width + x - self.rect.min.x) as usize;
+                if value > self.values[index] {
+                    self.values[index] = value;
+                }
+            }
+        }
+    }
+
+    pub fn draw<D: Display>(&self, display: &mut D, gray: u8) {
+        let width = self.rect.width() as i32;
+        for y in self.rect.min.y..self.rect.max.y {
+            for x in self.rect.min.x..self.rect.max.x {
+                let value = self.values[((y - self.rect.min.y) * width + x - self.rect.min.x) as usize];
+                if value > 0.0 {
+                    display.blend_pixel(x as u32, y as u32, gray, value);
+                }
+            }
+        }
+    }
+}
+
+// Ordered dithering thresholds.
+const BAYER_4X4: [[u8; 4]; 4] = [[ 0,  8,  2, 10],
+                                 [12,  4, 14,  6],
+                                 [ 3, 11,  1,  9],
+                                 [15,  7, 13,  5]];
+
+// Turns the gray levels into black and white patterns, for the `Fast` mode.
+pub struct Dithered<'a, D: Display + 'a> {
+    display: &'a mut D,
+}
+
+impl<'a, D: Display> Dithered<'a, D> {
+    pub fn new(display: &'a mut D) -> Dithered<'a, D> {
+        Dithered {
+            display,
+        }
+    }
+}
+
+impl<'a, D: Display> Display for Dithered<'a, D> {
+    fn set_pixel(&mut self, x: u32, y: u32, gray: u8) {
+        let threshold = BAYER_4X4[(y % 4) as usize][(x % 4) as usize] * 16 + 8;
+        let gray = if gray > threshold { 0xff } else { 0x00 };
+        self.display.set_pixel(x, y, gray);
+    }
+
+    // Black and white aren't dithered.
+    fn draw_span(&mut self, y: u32, x_min: u32, x_max: u32, gray: u8) {
+        if gray == 0x00 || gray == 0xff {
+            self.display.draw_span(y, x_min, x_max, gray);
+        } else {
+            for x in x_min..x_max {
+                self.set_pixel(x, y, gray);
+            }
+        }
+    }
+
+    forward_display!();
+}
+
+// Restricts the drawing operations of a display to a rectangle.
+pub struct Clipped<'a, D: Display + 'a> {
+    display: &'a mut D,
+    rect: Rectangle,
+}
+
+impl<'a, D: Display> Clipped<'a, D> {
+    pub fn new(display: &'a mut D, rect: Rectangle) -> Clipped<'a, D> {
+        Clipped {
+            display,
+            rect,
+        }
+    }
+}
+
+impl<'a, D: Display> Display for Clipped<'a, D> {
+    fn set_pixel(&mut self, x: u32, y: u32, gray: u8) {
+        if self.rect.contains(&Point::new(x as i32, y as i32)) {
+            self.display.set_pixel(x, y, gray);
+        }
+    }
+
+    fn draw_span(&mut self, y: u32, x_min: u32, x_max: u32, gray: u8) {
+        let y = y as i32;
+        if y < self.rect.min.y || y >= self.rect.max.y {
+            return;
+        }
+        let x_min = cmp::max(x_min as i32, self.rect.min.x);
+        let x_max = cmp::min(x_max as i32, self.rect.max.x);
+        if x_min < x_max {
+            self.display.draw_span(y as u32, x_min as u32, x_max as u32, gray);
+        }
+    }
+
+    forward_display!();
+}
+
+#[cfg(test)]
+mod tests {
+    use headless::HeadlessDisplay;
+    use super::{Display, Clipped, Dithered};
+
+    #[test]
+    fn fill_rect_is_clipped_to_the_display() {
+        let mut display = HeadlessDisplay::new(10, 8);
+        display.fill_rect(&rect!(-5, 6, 4, 20), 0x00);
+        for y in 0..8 {
+            for x in 0..10 {
+                let expected = if x < 4 && y >= 6 { 0x00 } else { 0xff };
+                assert_eq!(display.pixel(x, y), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn disks_are_filled_row_by_row() {
+        let mut display = HeadlessDisplay::new(20, 20);
+        let rect = display.draw_disk(&pt!(3, 10), 5, 0x00);
+        assert_eq!(rect, rect!(0, 5, 9, 16));
+        for y in 0..20 {
+            for x in 0..20 {
+                let inside = (x - 3) * (x - 3) + (y - 10) * (y - 10) <= 25;
+                assert_eq!(display.pixel(x as u32, y as u32) == 0x00, inside, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn spans_of_adaptors() {
+        let mut display = HeadlessDisplay::new(10, 8);
+        Clipped::new(&mut display, rect!(2, 0, 5, 4)).draw_span(1, 0, 10, 0x00);
+        Clipped::new(&mut display, rect!(2, 0, 5, 4)).draw_span(5, 0, 10, 0x00);
+        assert_eq!(display.pixels.iter().filter(|&&gray| gray == 0x00).count(), 3);
+        assert_eq!(display.pixel(2, 1), 0x00);
+        assert_eq!(display.pixel(4, 1), 0x00);
+        Dithered::new(&mut display).draw_span(3, 0, 10, 0x80);
+        let blacks = (0..10).filter(|&x| display.pixel(x, 3) == 0x00).count();
+        assert!(blacks > 0 && blacks < 10);
+    }
+}
@@ -0,0 +1,769 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::cmp;
+use std::mem;
+use display::{Display, Clipped, Coverage};
+use canvas::Canvas;
+use geom::{Point, Rectangle, segments_distance};
+use pixmap::Pixmap;
+use template::Template;
+
+const HISTORY_SIZE: usize = 64;
+// Length, in pixels, of the pieces of a smoothed segment.
+const PIECE_LENGTH: f32 = 4.0;
+const MAX_PIECES: usize = 32;
+
+#[derive(Debug, Copy, Clone)]
+pub struct StrokePoint {
+    pub position: Point,
+    pub time: f64,
+    pub pressure: f32,
+}
+
+// Maps a pressure to a fraction of the stroke width:
+// `min + (1 - min) * pressure ^ gamma`.
+#[derive(Debug, Copy, Clone)]
+pub struct PressureCurve {
+    pub min: f32,
+    pub gamma: f32,
+}
+
+impl PressureCurve {
+    pub fn apply(&self, pressure: f32) -> f32 {
+        self.min + (1.0 - self.min) * pressure.powf(self.gamma)
+    }
+}
+
+impl Default for PressureCurve {
+    fn default() -> PressureCurve {
+        PressureCurve {
+            min: 0.5,
+            gamma: 1.0,
+        }
+    }
+}
+
+// The layers of a document, from bottom to top.
+// The background layer holds the background image and the template,
+// the other layers hold strokes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Layer {
+    Background,
+    Ink,
+    Annotation,
+}
+
+pub const LAYERS: [Layer; 3] = [Layer::Background, Layer::Ink, Layer::Annotation];
+
+impl Layer {
+    pub fn parse(name: &str) -> Option<Layer> {
+        match name {
+            "background" => Some(Layer::Background),
+            "ink" => Some(Layer::Ink),
+            "annotation" => Some(Layer::Annotation),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Tool {
+    Pen,
+    // Removes the pixels of its layer.
+    Eraser,
+}
+
+// A stroke is rendered by joining its consecutive points, either with straight
+// segments or with Catmull-Rom splines if the stroke is smooth.
+// The width of the stroke at a given point depends on the pressure at this point.
+#[derive(Debug, Clone)]
+pub struct Stroke {
+    pub points: Vec<StrokePoint>,
+    pub tool: Tool,
+    pub width: f32,
+    pub color: u8,
+    pub curve: PressureCurve,
+    pub smooth: bool,
+    pub layer: Layer,
+}
+
+impl Stroke {
+    pub fn new(tool: Tool, width: f32, color: u8, curve: PressureCurve, smooth: bool) -> Stroke {
+        Stroke {
+            points: Vec::new(),
+            tool,
+            width,
+            color,
+            curve,
+            smooth,
+            layer: Layer::Ink,
+        }
+    }
+
+    pub fn radius(&self, index: usize) -> f32 {
+        self.width * self.curve.apply(self.points[index].pressure) / 2.0
+    }
+
+    pub fn push(&mut self, position: Point, time: f64, pressure: f32) {
+        self.points.push(StrokePoint {
+            position,
+            time,
+            pressure,
+        });
+    }
+
+    // The rectangle covered by the stroke, including its width.
+    pub fn bounding_box(&self) -> Option<Rectangle> {
+        if self.smooth {
+            let mut bounds: Option<Rectangle> = None;
+            for index in 0..self.points.len() {
+                absorb(&mut bounds, &self.segment_bounds(index, true));
+            }
+            return bounds;
+        }
+        let mut points = self.points.iter();
+        // One more pixel for the anti-aliased edges.
+        let radius = (self.width / 2.0).ceil() as i32 + 1;
+        points.next().map(|first| {
+            let mut rect = Rectangle::from_point(&first.position);
+            for sp in points {
+                rect.merge(&sp.position);
+            }
+            rect!(rect.min - radius, rect.max + radius)
+        })
+    }
+
+    // The stroke, as drawn on the screen, as polylines to be stroked with round caps and joins.
+    // Each polyline has a constant radius: the radii of the pieces are rounded to quarter pixels.
+    pub fn polylines(&self) -> Vec<(f32, Vec<(f32, f32)>)> {
+        let mut polylines: Vec<(f32, Vec<(f32, f32)>)> = Vec::new();
+        // A lone point is a segment of null length.
+        let start = if self.points.len() > 1 { 1 } else { 0 };
+        for index in start..self.points.len() {
+            let polyline = self.segment_polyline(index, self.smooth);
+            for piece in polyline.windows(2) {
+                let ((a, r1), (b, r2)) = (piece[0], piece[1]);
+                // Thin pieces are drawn as lines on the screen.
+                let radius = ((r1 + r2) * 2.0).round().max(2.0) / 4.0;
+                match polylines.last_mut() {
+                    Some(&mut (r, ref mut points)) if r == radius => points.push(b.into()),
+                    _ => polylines.push((radius, vec![a.into(), b.into()])),
+                }
+            }
+        }
+        polylines
+    }
+
+    // Whether the stroke passes within `radius` of the segment `[start, end]`.
+    pub fn intersects(&self, start: &Point, end: &Point, radius: f32) -> bool {
+        let reach = radius.ceil() as i32;
+        let mut rect = Rectangle::from_point(start);
+        rect.merge(end);
+        let rect = rect!(rect.min - reach, rect.max + reach);
+        if !self.bounding_box().is_some_and(|bb| bb.overlaps(&rect)) {
+            return false;
+        }
+        let reach = radius + self.width / 2.0;
+        (0..self.points.len()).any(|index| {
+            let a = self.points[index.saturating_sub(1)].position;
+            let b = self.points[index].position;
+            segments_distance(&a, &b, start, end) <= reach
+        })
+    }
+
+    // The points, with their radii, of the polyline that approximates the segment
+    // that ends at the point of the given index.
+    fn segment_polyline(&self, index: usize, smooth: bool) -> Vec<(Point, f32)> {
+        let start_index = index.saturating_sub(1);
+        let (p1, p2) = (self.points[start_index].position, self.points[index].position);
+        let (r1, r2) = (self.radius(start_index), self.radius(index));
+        if !smooth || p1 == p2 {
+            return vec![(p1, r1), (p2, r2)];
+        }
+        // The missing neighbors of the end points are replaced by the end points.
+        let p0 = self.points[start_index.saturating_sub(1)].position;
+        let p3 = self.points.get(index + 1).map_or(p2, |sp| sp.position);
+        let count = ((p2 - p1).length() / PIECE_LENGTH) as usize;
+        let count = count.clamp(1, MAX_PIECES);
+        (0..count + 1).map(|k| {
+            let t = k as f32 / count as f32;
+            (catmull_rom(&p0, &p1, &p2, &p3, t), r1 + (r2 - r1) * t)
+        }).collect()
+    }
+
+    pub fn segment_bounds(&self, index: usize, smooth: bool) -> Rectangle {
+        let polyline = self.segment_polyline(index, smooth);
+        let mut rect = Rectangle::from_point(&polyline[0].0);
+        let mut radius = 0;
+        for &(pt, r) in &polyline {
+            rect.merge(&pt);
+            radius = cmp::max(radius, r.ceil() as i32 + 1);
+        }
+        rect!(rect.min - radius, rect.max + radius)
+    }
+
+    fn draw_segment<D: Display>(&self, display: &mut D, index: usize, smooth: bool) -> Rectangle {
+        let polyline = self.segment_polyline(index, smooth);
+        let mut bounds = Rectangle::from_point(&polyline[0].0);
+        for piece in polyline.windows(2) {
+            let ((start, start_radius), (end, end_radius)) = (piece[0], piece[1]);
+            let (start_radius, end_radius) = (start_radius.round() as u32, end_radius.round() as u32);
+            if start_radius == 0 && end_radius == 0 {
+                display.draw_line_segment(&start, &end, self.color);
+                bounds.merge(&start);
+                bounds.merge(&end);
+            } else {
+                let rect = display.draw_thick_segment(&start, &end, start_radius, end_radius, self.color);
+                bounds.absorb(&rect);
+            }
+        }
+        bounds
+    }
+
+    // Draw the segment that ends at the point of the given index.
+    // A smooth segment is only final once the next point is known.
+    pub fn render_segment<D: Display>(&self, display: &mut D, index: usize) -> Rectangle {
+        self.draw_segment(display, index, self.smooth)
+    }
+
+    // Draw the segment that ends at the point of the given index as a straight segment.
+    pub fn render_straight_segment<D: Display>(&self, display: &mut D, index: usize) -> Rectangle {
+        self.draw_segment(display, index, false)
+    }
+
+    // Draw, within `rect`, the whole stroke, with anti-aliased edges if `antialias` is set.
+    pub fn render_with<D: Display>(&self, display: &mut D, rect: &Rectangle, antialias: bool) {
+        if antialias {
+            self.render_antialiased(display, rect);
+        } else {
+            self.render_rect(display, rect, 0..self.points.len(), self.smooth);
+        }
+    }
+
+    // Draw, within `rect`, the whole stroke with anti-aliased edges.
+    // The resulting gray levels can't be shown by the `Fast` mode.
+    pub fn render_antialiased<D: Display>(&self, display: &mut D, rect: &Rectangle) {
+        let rect = match self.bounding_box().and_then(|bb| bb.intersection(rect)) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let mut coverage = Coverage::new(rect);
+        for index in 0..self.points.len() {
+            if !self.segment_bounds(index, self.smooth).overlaps(&rect) {
+                continue;
+            }
+            for piece in self.segment_polyline(index, self.smooth).windows(2) {
+                let ((start, start_radius), (end, end_radius)) = (piece[0], piece[1]);
+                // Thin strokes are drawn as one pixel wide lines.
+                coverage.add_thick_segment(&start, &end, start_radius.max(0.5), end_radius.max(0.5));
+            }
+        }
+        coverage.draw(display, self.color);
+    }
+
+    // Draw, within `rect`, the segments of the given range that overlap `rect`.
+    pub fn render_rect<D: Display>(&self, display: &mut D, rect: &Rectangle, range: Range<usize>, smooth: bool) {
+        let mut clipped = Clipped::new(display, *rect);
+        for index in range {
+            if self.segment_bounds(index, smooth).overlaps(rect) {
+                self.draw_segment(&mut clipped, index, smooth);
+            }
+        }
+    }
+}
+
+fn catmull_rom(p0: &Point, p1: &Point, p2: &Point, p3: &Point, t: f32) -> Point {
+    let (t2, t3) = (t * t, t * t * t);
+    let interpolate = |a: i32, b: i32, c: i32, d: i32| -> i32 {
+        let (a, b, c, d) = (a as f32, b as f32, c as f32, d as f32);
+        (0.5 * (2.0 * b + (c - a) * t +
+                (2.0 * a - 5.0 * b + 4.0 * c - d) * t2 +
+                (3.0 * b - a - 3.0 * c + d) * t3)).round() as i32
+    };
+    Point::new(interpolate(p0.x, p1.x, p2.x, p3.x),
+               interpolate(p0.y, p1.y, p2.y, p3.y))
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    Add(Stroke),
+    Clear(Vec<Stroke>),
+    // The removed strokes, with their indices at the time of the removal.
+    Erase(Vec<(usize, Stroke)>),
+    // The removed background image and strokes of the background layer.
+    ClearBackground(Option<Pixmap>, Vec<(usize, Stroke)>),
+}
+
+// The actions recorded since the first finger of a group was put down.
+#[derive(Debug, Clone)]
+struct Group {
+    // Length of the undo stack when the group started.
+    start: usize,
+    // The redo stack cleared by the actions of the group.
+    redo_stack: Option<Vec<Action>>,
+}
+
+// A drawing: the strokes are stored in the order they were drawn.
+// The last `HISTORY_SIZE` actions can be undone.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub dims: (u32, u32),
+    pub strokes: Vec<Stroke>,
+    // An image, of the same dimensions as the document, drawn below the strokes.
+    pub background: Option<Pixmap>,
+    // Drawn between the background and the strokes.
+    pub template: Option<Template>,
+    undo_stack: VecDeque<Action>,
+    redo_stack: Vec<Action>,
+    group: Option<Group>,
+}
+
+impl Document {
+    pub fn new(dims: (u32, u32)) -> Document {
+        Document {
+            dims,
+            strokes: Vec::new(),
+            background: None,
+            template: None,
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            group: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strokes.is_empty()
+    }
+
+    fn record(&mut self, action: Action) {
+        if self.undo_stack.len() == HISTORY_SIZE {
+            self.undo_stack.pop_front();
+            if let Some(ref mut group) = self.group {
+                group.start = group.start.saturating_sub(1);
+            }
+        }
+        self.undo_stack.push_back(action);
+        self.clear_redo();
+    }
+
+    // The redo stack is kept aside until the group is known not to be a gesture.
+    fn clear_redo(&mut self) {
+        if let Some(ref mut group) = self.group {
+            if group.redo_stack.is_none() && !self.redo_stack.is_empty() {
+                group.redo_stack = Some(self.redo_stack.drain(..).collect());
+            }
+        }
+        self.redo_stack.clear();
+    }
+
+    // Length of the undo stack when the current group started.
+    fn group_start(&self) -> usize {
+        self.group.as_ref().map_or(self.undo_stack.len(), |group| group.start)
+    }
+
+    // Starts a new group of actions, that can be retracted if it turns out to be a gesture.
+    pub fn begin_group(&mut self) {
+        self.group = Some(Group {
+            start: self.undo_stack.len(),
+            redo_stack: None,
+        });
+    }
+
+    pub fn push(&mut self, stroke: Stroke) {
+        self.record(Action::Add(stroke.clone()));
+        self.strokes.push(stroke);
+    }
+
+    pub fn clear(&mut self) {
+        if !self.strokes.is_empty() {
+            let strokes = self.strokes.drain(..).collect();
+            self.record(Action::Clear(strokes));
+        }
+    }
+
+    // Removes the strokes of the given layer and returns the region that needs to be redrawn.
+    pub fn clear_layer(&mut self, layer: Layer) -> Option<Rectangle> {
+        let (erased, dirty) = self.remove_layer(layer);
+        if !erased.is_empty() {
+            self.record(Action::Erase(erased));
+        }
+        dirty
+    }
+
+    // Removes the background image and the strokes of the background layer.
+    // Returns the region that needs to be redrawn.
+    pub fn clear_background(&mut self) -> Option<Rectangle> {
+        let (erased, dirty) = self.remove_layer(Layer::Background);
+        if self.background.is_none() && erased.is_empty() {
+            return None;
+        }
+        let background = self.background.take();
+        let dirty = if background.is_some() { Some(self.bounds()) } else { dirty };
+        self.record(Action::ClearBackground(background, erased));
+        dirty
+    }
+
+    // Returns the removed strokes, with their indices at the time of the removal, and the region they covered.
+    fn remove_layer(&mut self, layer: Layer) -> (Vec<(usize, Stroke)>, Option<Rectangle>) {
+        let mut erased = Vec::new();
+        let mut dirty: Option<Rectangle> = None;
+        let mut index = 0;
+        while index < self.strokes.len() {
+            if self.strokes[index].layer == layer {
+                let stroke = self.strokes.remove(index);
+                if let Some(rect) = stroke.bounding_box() {
+                    absorb(&mut dirty, &rect);
+                }
+                erased.push((index, stroke));
+            } else {
+                index += 1;
+            }
+        }
+        (erased, dirty)
+    }
+
+    // Removes the strokes of `layer` that pass within `radius` of the segment `[start, end]`
+    // and returns the region that needs to be redrawn.
+    // If `merge` is set, the removal is appended to the previous erasure.
+    pub fn erase(&mut self, start: &Point, end: &Point, radius: f32, merge: bool, layer: Layer) -> Option<Rectangle> {
+        let mut erased = Vec::new();
+        let mut dirty: Option<Rectangle> = None;
+        let mut index = 0;
+        while index < self.strokes.len() {
+            let stroke = &self.strokes[index];
+            if stroke.layer == layer && stroke.tool == Tool::Pen && stroke.intersects(start, end, radius) {
+                let stroke = self.strokes.remove(index);
+                if let Some(rect) = stroke.bounding_box() {
+                    absorb(&mut dirty, &rect);
+                }
+                erased.push((index, stroke));
+            } else {
+                index += 1;
+            }
+        }
+        if !erased.is_empty() {
+            // The erasures of a group are kept apart from the previous ones.
+            let merge = merge && self.undo_stack.len() > self.group_start();
+            if let (true, Some(&mut Action::Erase(ref mut previous))) = (merge, self.undo_stack.back_mut()) {
+                previous.extend(erased);
+                self.clear_redo();
+                return dirty;
+            }
+            self.record(Action::Erase(erased));
+        }
+        dirty
+    }
+
+    // The rectangle covered by the strokes.
+    pub fn bounding_box(&self) -> Option<Rectangle> {
+        let mut bounds: Option<Rectangle> = None;
+        for stroke in &self.strokes {
+            if let Some(rect) = stroke.bounding_box() {
+                absorb(&mut bounds, &rect);
+            }
+        }
+        bounds.and_then(|rect| self.bounds().intersection(&rect))
+    }
+
+    // The pens of a layer, split at the erasers of the layer: each run of consecutive pens
+    // comes with the indices of the erasers drawn after it, which remove its pixels.
+    pub fn erased_runs(&self, layer: Layer) -> Vec<(Vec<&Stroke>, Vec<usize>)> {
+        let mut runs = Vec::new();
+        let mut erasers = Vec::new();
+        let mut run = Vec::new();
+        for (index, stroke) in self.strokes.iter().enumerate().filter(|&(_, s)| s.layer == layer) {
+            if stroke.tool == Tool::Eraser {
+                if !run.is_empty() {
+                    runs.push((mem::take(&mut run), erasers.len()));
+                }
+                erasers.push(index);
+            } else {
+                run.push(stroke);
+            }
+        }
+        if !run.is_empty() {
+            runs.push((run, erasers.len()));
+        }
+        runs.into_iter().map(|(run, start)| (run, erasers[start..].to_vec())).collect()
+    }
+
+    // The indices of the erasers that remove the template: the template shares
+    // its pixels with the background layer, and can be erased from every layer.
+    pub fn template_erasers(&self) -> Vec<usize> {
+        let erasable = self.template.as_ref().is_some_and(|t| t.erasable);
+        (0..self.strokes.len()).filter(|&index| {
+            let stroke = &self.strokes[index];
+            stroke.tool == Tool::Eraser && (erasable || stroke.layer == Layer::Background)
+        }).collect()
+    }
+
+    fn bounds(&self) -> Rectangle {
+        rect!(0, 0, self.dims.0 as i32, self.dims.1 as i32)
+    }
+
+    // Reverts the last action and returns the region that needs to be redrawn.
+    pub fn undo(&mut self) -> Option<Rectangle> {
+        self.group = None;
+        self.undo_stack.pop_back().map(|action| {
+            let rect = self.revert(&action);
+            self.redo_stack.push(action);
+            rect
+        })
+    }
+
+    // Reverts the given action, which must be the last one applied.
+    fn revert(&mut self, action: &Action) -> Rectangle {
+        match *action {
+            Action::Add(_) => {
+                self.strokes.pop().and_then(|stroke| stroke.bounding_box())
+                                  .unwrap_or_else(|| self.bounds())
+            },
+            Action::Clear(ref strokes) => {
+                self.strokes.extend_from_slice(strokes);
+                self.bounds()
+            },
+            Action::Erase(ref erased) => {
+                let mut dirty: Option<Rectangle> = None;
+                for &(index, ref stroke) in erased.iter().rev() {
+                    if let Some(rect) = stroke.bounding_box() {
+                        absorb(&mut dirty, &rect);
+                    }
+                    self.strokes.insert(index, stroke.clone());
+                }
+                dirty.unwrap_or_else(|| self.bounds())
+            },
+            Action::ClearBackground(ref background, ref erased) => {
+                self.background = background.clone();
+                for &(index, ref stroke) in erased.iter().rev() {
+                    self.strokes.insert(index, stroke.clone());
+                }
+                self.bounds()
+            },
+        }
+    }
+
+    // Reapplies the last undone action and returns the region that needs to be redrawn.
+    pub fn redo(&mut self) -> Option<Rectangle> {
+        self.group = None;
+        self.redo_stack.pop().map(|action| {
+            let rect = match action {
+                Action::Add(ref stroke) => {
+                    self.strokes.push(stroke.clone());
+                    stroke.bounding_box().unwrap_or_else(|| self.bounds())
+                },
+                Action::Clear(_) => {
+                    self.strokes.clear();
+                    self.bounds()
+                },
+                Action::Erase(ref erased) => {
+                    let mut dirty: Option<Rectangle> = None;
+                    for &(index, ref stroke) in erased {
+                        if let Some(rect) = stroke.bounding_box() {
+                            absorb(&mut dirty, &rect);
+                        }
+                        self.strokes.remove(index);
+                    }
+                    dirty.unwrap_or_else(|| self.bounds())
+                },
+                Action::ClearBackground(_, ref erased) => {
+                    self.background = None;
+                    for &(index, _) in erased {
+                        self.strokes.remove(index);
+                    }
+                    self.bounds()
+                },
+            };
+            self.undo_stack.push_back(action);
+            rect
+        })
+    }
+
+    // Forgets the last `count` strokes and the erasures of the current group, as if they never happened.
+    // The redo stack is restored once the whole group is forgotten.
+    // Returns the region that needs to be redrawn.
+    pub fn retract(&mut self, count: usize) -> Option<Rectangle> {
+        let mut dirty: Option<Rectangle> = None;
+        let start = self.group_start();
+        let mut retracted = 0;
+        while self.undo_stack.len() > start {
+            match self.undo_stack.back() {
+                Some(&Action::Add(_)) if retracted < count => retracted += 1,
+                Some(&Action::Erase(_)) => (),
+                _ => break,
+            }
+            if let Some(action) = self.undo_stack.pop_back() {
+                let rect = self.revert(&action);
+                absorb(&mut dirty, &rect);
+            }
+        }
+        if self.undo_stack.len() == start {
+            if let Some(redo_stack) = self.group.as_mut().and_then(|group| group.redo_stack.take()) {
+                self.redo_stack = redo_stack;
+            }
+        }
+        dirty
+    }
+
+    // Renders the given region of the given layers, with anti-aliased strokes if `antialias` is set.
+    // Each layer is drawn on its own canvas, the canvases are then composited on a white background.
+    // Returns the part of the region that lies within the document.
+    pub fn render_rect<D: Display>(&self, display: &mut D, rect: &Rectangle, layers: &[Layer], antialias: bool) -> Option<Rectangle> {
+        self.bounds().intersection(rect).inspect(|&rect| {
+            let background = self.background.as_ref().filter(|_| layers.contains(&Layer::Background));
+            let template = self.template.as_ref().filter(|_| layers.contains(&Layer::Background));
+            let strokes: Vec<Vec<&Stroke>> = LAYERS.iter().map(|layer| {
+                if !layers.contains(layer) {
+                    return Vec::new();
+                }
+                self.strokes.iter().filter(|s| {
+                    s.layer == *layer && s.bounding_box().is_some_and(|bb| bb.overlaps(&rect))
+                }).collect()
+            }).collect();
+            // Nothing to composite.
+            if background.is_none() && template.is_none() && strokes.iter().all(|s| s.is_empty()) {
+                display.fill_rect(&rect, 0xff);
+                return;
+            }
+            let mut result = Canvas::new(rect);
+            if let Some(background) = background {
+                for y in rect.min.y..rect.max.y {
+                    for x in rect.min.x..rect.max.x {
+                        result.set_pixel(x as u32, y as u32, background.get_pixel(x as u32, y as u32));
+                    }
+                }
+            }
+            // The template shares its canvas with the strokes of the background layer.
+            if template.is_some() || !strokes[0].is_empty() {
+                let mut canvas = Canvas::new(rect);
+                if let Some(template) = template {
+                    for y in rect.min.y..rect.max.y {
+                        // The lines are drawn as spans of consecutive pixels.
+                        let mut x = rect.min.x;
+                        while x < rect.max.x {
+                            if let Some(gray) = template.gray(x as u32, y as u32) {
+                                let start = x;
+                                x += 1;
+                                while x < rect.max.x && template.gray(x as u32, y as u32) == Some(gray) {
+                                    x += 1;
+                                }
+                                canvas.draw_span(y as u32, start as u32, x as u32, gray);
+                            } else {
+                                x += 1;
+                            }
+                        }
+                    }
+                    // An erasable template is erased by the erasers of every layer.
+                    if template.erasable {
+                        canvas.erasing = true;
+                        for stroke in self.strokes.iter().filter(|s| s.tool == Tool::Eraser) {
+                            stroke.render_with(&mut canvas, &rect, antialias);
+                        }
+                    }
+                }
+                for stroke in &strokes[0] {
+                    canvas.erasing = stroke.tool == Tool::Eraser;
+                    stroke.render_with(&mut canvas, &rect, antialias);
+                }
+                result.composite(&canvas);
+            }
+            // The layers without strokes in the region are skipped.
+            for strokes in strokes[1..].iter().filter(|s| !s.is_empty()) {
+                let mut canvas = Canvas::new(rect);
+                for stroke in strokes {
+                    canvas.erasing = stroke.tool == Tool::Eraser;
+                    stroke.render_with(&mut canvas, &rect, antialias);
+                }
+                result.composite(&canvas);
+            }
+            result.draw(display);
+        })
+    }
+}
+
+pub fn absorb(dirty: &mut Option<Rectangle>, rect: &Rectangle) {
+    if let Some(ref mut dirty) = *dirty {
+        dirty.absorb(rect);
+    } else {
+        *dirty = Some(*rect);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use headless::HeadlessDisplay;
+    use pixmap::Pixmap;
+    use super::{Document, Stroke, Tool, Layer, LAYERS, PressureCurve};
+
+    #[test]
+    fn background_strokes_are_rendered() {
+        let mut document = Document::new((100, 100));
+        let mut stroke = Stroke::new(Tool::Pen, 4.0, 0x00, PressureCurve::default(), false);
+        stroke.layer = Layer::Background;
+        stroke.push(pt!(10, 50), 0.0, 1.0);
+        stroke.push(pt!(90, 50), 0.1, 1.0);
+        document.push(stroke);
+        let mut display = HeadlessDisplay::new(100, 100);
+        document.render_rect(&mut display, &rect!(0, 0, 100, 100), &LAYERS, false);
+        assert_eq!(display.pixel(50, 50), 0x00);
+        document.render_rect(&mut display, &rect!(0, 0, 100, 100), &[Layer::Ink], false);
+        assert_eq!(display.pixel(50, 50), 0xff);
+    }
+
+    #[test]
+    fn clearing_the_background_can_be_undone() {
+        let mut document = Document::new((100, 100));
+        document.background = Some(Pixmap::new(100, 100));
+        for &layer in &[Layer::Background, Layer::Ink] {
+            let mut stroke = Stroke::new(Tool::Pen, 4.0, 0x00, PressureCurve::default(), false);
+            stroke.layer = layer;
+            stroke.push(pt!(10, 50), 0.0, 1.0);
+            document.push(stroke);
+        }
+        assert_eq!(document.clear_background(), Some(rect!(0, 0, 100, 100)));
+        assert!(document.background.is_none());
+        assert_eq!(document.strokes.len(), 1);
+        assert_eq!(document.strokes[0].layer, Layer::Ink);
+        assert_eq!(document.clear_background(), None);
+        document.undo();
+        assert!(document.background.is_some());
+        assert_eq!(document.strokes[0].layer, Layer::Background);
+        document.redo();
+        assert!(document.background.is_none());
+        assert_eq!(document.strokes.len(), 1);
+    }
+
+    #[test]
+    fn strokes_of_constant_width_are_single_polylines() {
+        let mut stroke = Stroke::new(Tool::Pen, 4.0, 0x00, PressureCurve::default(), true);
+        for i in 0..10 {
+            stroke.push(pt!(10 + 20 * i, 50 + (i % 3) * 10), i as f64 * 0.1, 1.0);
+        }
+        let polylines = stroke.polylines();
+        assert_eq!(polylines.len(), 1);
+        assert_eq!(polylines[0].0, 2.0);
+        assert_eq!(polylines[0].1.first(), Some(&(10.0, 50.0)));
+        assert_eq!(polylines[0].1.last(), Some(&(190.0, 50.0)));
+        stroke.push(pt!(200, 50), 1.0, 0.0);
+        assert!(stroke.polylines().len() > 1);
+    }
+
+    #[test]
+    fn pens_are_erased_by_the_later_erasers_of_their_layer() {
+        let mut document = Document::new((100, 100));
+        let tools = [(Tool::Pen, Layer::Ink), (Tool::Eraser, Layer::Ink), (Tool::Pen, Layer::Annotation),
+                     (Tool::Pen, Layer::Ink), (Tool::Eraser, Layer::Ink)];
+        for &(tool, layer) in &tools {
+            let mut stroke = Stroke::new(tool, 4.0, 0x00, PressureCurve::default(), false);
+            stroke.layer = layer;
+            stroke.push(pt!(10, 50), 0.0, 1.0);
+            document.push(stroke);
+        }
+        let runs: Vec<(usize, Vec<usize>)> = document.erased_runs(Layer::Ink).into_iter()
+                                                     .map(|(pens, erasers)| (pens.len(), erasers)).collect();
+        assert_eq!(runs, vec![(1, vec![1, 4]), (1, vec![4])]);
+        let runs = document.erased_runs(Layer::Annotation);
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].1.is_empty());
+        assert!(document.erased_runs(Layer::Background).is_empty());
+    }
+}
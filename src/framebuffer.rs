@@ -2,19 +2,18 @@ extern crate libc;
 extern crate png;
 
 use std::ptr;
-use std::cmp;
-use std::mem::swap;
 use std::path::Path;
+use std::slice;
 use std::io;
 use std::fs::{OpenOptions, File};
-use std::slice;
 use std::borrow::Cow;
 use std::os::unix::io::AsRawFd;
 use std::ops::Drop;
 
 use libc::ioctl;
 use png::HasParameters;
-use geom::{Point, Rectangle};
+use geom::Rectangle;
+use display::{Display, Mode};
 
 const FBIOGET_VSCREENINFO: libc::c_ulong = 0x4600;
 const FBIOGET_FSCREENINFO: libc::c_ulong = 0x4602;
@@ -164,16 +163,7 @@ const TEMP_USE_AMBIENT: libc::c_int = 0x1000;
 const EPDC_FLAG_ENABLE_INVERSION: libc::c_uint = 0x01;
 const EPDC_FLAG_FORCE_MONOCHROME: libc::c_uint = 0x02;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum Mode {
-    Fast,
-    Partial,
-    Gui,
-    Full,
-}
-
-type SetPixelRgb = fn(&mut Framebuffer, u32, u32, [u8; 3]);
-type AsRgb = fn(&Framebuffer) -> Vec<u8>;
+type CopyRow = fn(&mut Framebuffer, u32, u32, u32);
 
 pub struct Framebuffer {
     device: File,
@@ -181,8 +171,14 @@ pub struct Framebuffer {
     frame_size: libc::size_t, 
     token: u32,
     flags: u32,
-    set_pixel_rgb: SetPixelRgb,
-    as_rgb: AsRgb,
+    copy_row: CopyRow,
+    // The pixel value of each gray level, in the format of the frame.
+    palette: Vec<u32>,
+    // The drawing operations go to this grayscale copy of the screen,
+    // the changed pixels are copied to the frame before each update.
+    shadow: Vec<u8>,
+    // The range of changed columns of each row of the shadow buffer.
+    dirty: Vec<Option<(u32, u32)>>,
     pub bytes_per_pixel: u8,
     pub var_info: VarScreenInfo,
     pub fix_info: FixScreenInfo,
@@ -197,6 +193,7 @@ impl Framebuffer {
         let fix_info = fix_screen_info(&device)?;
 
         assert_eq!(var_info.bits_per_pixel % 8, 0);
+        check_format(&var_info)?;
 
         let bytes_per_pixel = var_info.bits_per_pixel / 8;
 
@@ -218,95 +215,133 @@ impl Framebuffer {
         if frame == libc::MAP_FAILED {
             Err(io::Error::last_os_error())
         } else {
-            let (set_pixel_rgb, as_rgb): (SetPixelRgb, AsRgb) = if var_info.bits_per_pixel > 16 {
-                (set_pixel_rgb_32, as_rgb_32)
-            } else {
-                (set_pixel_rgb_16, as_rgb_16)
+            let copy_row: CopyRow = match bytes_per_pixel {
+                1 => copy_row_8,
+                2 => copy_row_16,
+                _ => copy_row_32,
             };
-            Ok(Framebuffer {
+            let palette = (0..256).map(|gray| encode_gray(&var_info, gray as u8)).collect();
+            let (width, height) = (var_info.xres, var_info.yres);
+            let mut fb = Framebuffer {
                    device: device,
                    frame: frame,
                    frame_size: frame_size,
                    token: 1,
                    flags: 0,
-                   set_pixel_rgb: set_pixel_rgb,
-                   as_rgb: as_rgb,
+                   copy_row,
+                   palette,
+                   shadow: Vec::new(),
+                   dirty: vec![None; height as usize],
                    bytes_per_pixel: bytes_per_pixel as u8,
                    var_info: var_info,
                    fix_info: fix_info,
-               })
+            };
+            // Start from the current content of the screen.
+            let fields = channels(&fb.var_info);
+            let mut shadow = Vec::with_capacity((width * height) as usize);
+            for y in 0..height {
+                for x in 0..width {
+                    shadow.push(decode_gray(&fields, fb.pixel_value(x, y)));
+                }
+            }
+            fb.shadow = shadow;
+            Ok(fb)
         }
     }
-    
-    pub fn set_pixel(&mut self, x: u32, y: u32, gray: u8) {
-        (self.set_pixel_rgb)(self, x, y, [gray, gray, gray]);
+
+    // The raw value of the pixel at the given position of the frame.
+    fn pixel_value(&self, x: u32, y: u32) -> u32 {
+        let bytes_per_pixel = self.bytes_per_pixel as usize;
+        let start = (self.var_info.xoffset + x) as usize * bytes_per_pixel +
+                    (self.var_info.yoffset + y) as usize * self.fix_info.line_length as usize;
+        let frame = unsafe { slice::from_raw_parts(self.frame as *const u8, self.frame_size) };
+        frame[start..start + bytes_per_pixel].iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
     }
 
-    pub fn draw_disk(&mut self, center: &Point, radius: u32, gray: u8) -> Rectangle {
-        let (width, height) = self.dims();
-        let x_min = cmp::max(0, center.x - radius as i32);
-        let x_max = cmp::min(width as i32, center.x + radius as i32 + 1);
-        let y_min = cmp::max(0, center.y - radius as i32);
-        let y_max = cmp::min(height as i32, center.y + radius as i32 + 1);
-        for x in x_min..x_max {
-            for y in y_min..y_max {
-                let pt = Point::new(x, y);
-                if (pt).dist2(center) <= radius.pow(2) {
-                    self.set_pixel(x as u32, y as u32, gray);
-                }
+    // Copy the changed pixels of the shadow buffer to the frame.
+    fn flush(&mut self) {
+        for y in 0..self.dirty.len() {
+            if let Some((x_min, x_max)) = self.dirty[y].take() {
+                (self.copy_row)(self, y as u32, x_min, x_max + 1);
             }
         }
-        rect!(x_min, y_min, x_max, y_max)
     }
 
-    // Bresenham's line algorithm
-    pub fn draw_line_segment(&mut self, start: &Point, end: &Point, gray: u8) {
-        let (mut x0, mut y0) = (start.x, start.y);
-        let (mut x1, mut y1) = (end.x, end.y);
+    // Extend the changed columns of row `y` to `x_min..x_max`.
+    fn mark_dirty(&mut self, y: u32, x_min: u32, x_max: u32) {
+        let span = self.dirty[y as usize].map_or((x_min, x_max - 1), |(a, b)| {
+            (a.min(x_min), b.max(x_max - 1))
+        });
+        self.dirty[y as usize] = Some(span);
+    }
 
-        let is_steep = (y1 - y0).abs() > (x1 - x0).abs();
+    pub fn toggle_monochrome(&mut self) {
+        self.flags ^= EPDC_FLAG_FORCE_MONOCHROME;
+    }
 
-        if is_steep {
-            swap(&mut x0, &mut y0);
-            swap(&mut x1, &mut y1);
-        }
+    pub fn id(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.fix_info.id)
+    }
 
-        if x0 > x1 {
-            swap(&mut x0, &mut x1);
-            swap(&mut y0, &mut y1);
-        }
+    pub fn length(&self) -> usize {
+        self.frame_size as usize
+    }
+}
 
-        let dx = x1 - x0;
-        let dy = (y1 - y0).abs();
-        let mut error = dx / 2;
+// The update and waveform modes sent to the driver for a given mode.
+pub fn update_params(mode: Mode) -> (u32, u32) {
+    match mode {
+        Mode::Fast    => (UPDATE_MODE_PARTIAL, NTX_WFM_MODE_A2),
+        Mode::Partial => (UPDATE_MODE_PARTIAL, WAVEFORM_MODE_AUTO),
+        Mode::Gui     => (UPDATE_MODE_FULL, WAVEFORM_MODE_AUTO),
+        Mode::Full    => (UPDATE_MODE_FULL, NTX_WFM_MODE_GC16),
+    }
+}
 
-        let y_step = (y1 - y0).signum();
-        let mut y = y0;
+impl Display for Framebuffer {
+    // Pixels outside of the screen are ignored.
+    fn set_pixel(&mut self, x: u32, y: u32, gray: u8) {
+        let (width, height) = self.dims();
+        if x >= width || y >= height {
+            return;
+        }
+        let index = (y * width + x) as usize;
+        if self.shadow[index] == gray {
+            return;
+        }
+        self.shadow[index] = gray;
+        self.mark_dirty(y, x, x + 1);
+    }
 
-        for x in x0..(x1 + 1) {
-            if is_steep {
-                self.set_pixel(y as u32, x as u32, gray);
-            } else {
-                self.set_pixel(x as u32, y as u32, gray);
-            }
-            error -= dy;
-            if error < 0 {
-                y = y + y_step;
-                error += dx;
-            }
+    fn draw_span(&mut self, y: u32, x_min: u32, x_max: u32, gray: u8) {
+        let (width, height) = self.dims();
+        let x_max = x_max.min(width);
+        if y >= height || x_min >= x_max {
+            return;
+        }
+        let row = (y * width) as usize;
+        for pixel in &mut self.shadow[row + x_min as usize..row + x_max as usize] {
+            *pixel = gray;
+        }
+        self.mark_dirty(y, x_min, x_max);
+    }
+
+    // Pixels outside of the screen are white.
+    fn get_pixel(&self, x: u32, y: u32) -> u8 {
+        let (width, height) = self.dims();
+        if x < width && y < height {
+            self.shadow[(y * width + x) as usize]
+        } else {
+            0xff
         }
     }
 
     // Tell the driver that the screen needs to be redrawn.
     // The `rect` parameter is ignored for the `Gui` and `Full` modes.
     // The `Fast` mode only understands the following gray levels: 0x00 and 0xFF.
-    pub fn update<T: Into<MxcfbRect>>(&mut self, rect: T, mode: Mode) -> io::Result<u32> {
-        let (update_mode, waveform_mode) = match mode {
-            Mode::Fast    => (UPDATE_MODE_PARTIAL, NTX_WFM_MODE_A2),
-            Mode::Partial => (UPDATE_MODE_PARTIAL, WAVEFORM_MODE_AUTO),
-            Mode::Gui     => (UPDATE_MODE_FULL, WAVEFORM_MODE_AUTO),
-            Mode::Full    => (UPDATE_MODE_FULL, NTX_WFM_MODE_GC16),
-        };
+    fn update(&mut self, rect: Rectangle, mode: Mode) -> io::Result<u32> {
+        self.flush();
+        let (update_mode, waveform_mode) = update_params(mode);
         let alt_buffer_data = MxcfbAltBufferData {
             virt_addr: ptr::null(),
             phys_addr: 0,
@@ -341,8 +376,7 @@ impl Framebuffer {
         }
     }
 
-    // Wait for a specific update to complete
-    pub fn wait(&mut self, token: u32) -> io::Result<i32> {
+    fn wait(&mut self, token: u32) -> io::Result<i32> {
         let result = unsafe {
             libc::ioctl(self.device.as_raw_fd(), MXCFB_WAIT_FOR_UPDATE_COMPLETE, &token)
         };
@@ -354,98 +388,140 @@ impl Framebuffer {
         }
     }
 
-    fn as_bytes(&self) -> &[u8] {
-        unsafe { slice::from_raw_parts(self.frame as *const u8, self.frame_size) }
-    }
-
-    pub fn save<P: AsRef<Path>>(&self, path: P) {
-        let (width, height) = self.dims();
-        let file = File::create(path).unwrap();
-        let mut encoder = png::Encoder::new(file, width, height);
-        encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&(self.as_rgb)(self)).unwrap();
+    fn dims(&self) -> (u32, u32) {
+        (self.var_info.xres, self.var_info.yres)
     }
 
-    pub fn toggle_inverse(&mut self) {
+    fn toggle_inverse(&mut self) {
         self.flags ^= EPDC_FLAG_ENABLE_INVERSION;
     }
 
-    pub fn toggle_monochrome(&mut self) {
-        self.flags ^= EPDC_FLAG_FORCE_MONOCHROME;
-    }
-
-    pub fn dims(&self) -> (u32, u32) {
-        (self.var_info.xres, self.var_info.yres)
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if self.is_color() {
+            save_rgb(path, self.dims(), &self.shadow)
+        } else {
+            save_gray(path, self.dims(), &self.shadow)
+        }
     }
 
-    pub fn id(&self) -> Cow<str> {
-        String::from_utf8_lossy(&self.fix_info.id)
+    // Color frames are saved as RGB images.
+    fn is_color(&self) -> bool {
+        channels(&self.var_info).len() > 1
     }
+}
 
-    pub fn length(&self) -> usize {
-        self.frame_size as usize
-    }
+// Write 8 bits grayscale data as a PNG file.
+pub fn save_gray(path: &Path, dims: (u32, u32), gray8: &[u8]) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(file, dims.0, dims.1);
+    encoder.set(png::ColorType::Grayscale).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(gray8)?;
+    Ok(())
 }
 
-#[inline]
-pub fn set_pixel_rgb_16(fb: &mut Framebuffer, x: u32, y: u32, rgb: [u8; 3]) {
-    let addr = (fb.var_info.xoffset as isize + x as isize) * (fb.bytes_per_pixel as isize) +
-               (fb.var_info.yoffset as isize + y as isize) * (fb.fix_info.line_length as isize);
+// Write 8 bits grayscale data as an RGB PNG file.
+pub fn save_rgb(path: &Path, dims: (u32, u32), gray8: &[u8]) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(file, dims.0, dims.1);
+    encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let rgb: Vec<u8> = gray8.iter().flat_map(|&gray| [gray, gray, gray]).collect();
+    writer.write_image_data(&rgb)?;
+    Ok(())
+}
 
-    assert!(addr < fb.frame_size as isize);
+// Only packed pixels with the most significant bits on the left are supported.
+// A `grayscale` value greater than one is a FOURCC code.
+fn check_format(var_info: &VarScreenInfo) -> io::Result<()> {
+    if var_info.grayscale > 1 {
+        return Err(io::Error::other(format!("unsupported pixel format: {:#x}", var_info.grayscale)));
+    }
+    let fields = [&var_info.red, &var_info.green, &var_info.blue, &var_info.transp];
+    if fields.iter().any(|field| field.msb_right != 0) {
+        return Err(io::Error::other("unsupported bit order: most significant bit on the right"));
+    }
+    Ok(())
+}
 
-    unsafe {
-        let spot = fb.frame.offset(addr) as *mut u8;
-        *spot.offset(0) = rgb[2] >> 3 | (rgb[1] & 0b00011100) << 3;
-        *spot.offset(1) = (rgb[0] & 0b11111000) | rgb[1] >> 5;
+// The channels of a pixel: a single one for grayscale frames.
+fn channels(var_info: &VarScreenInfo) -> Vec<Bitfield> {
+    if var_info.grayscale == 1 || var_info.bits_per_pixel == 8 {
+        if var_info.red.length > 0 {
+            vec![var_info.red.clone()]
+        } else {
+            vec![Bitfield { offset: 0, length: var_info.bits_per_pixel, msb_right: 0 }]
+        }
+    } else {
+        vec![var_info.red.clone(), var_info.green.clone(), var_info.blue.clone()]
     }
 }
 
-#[inline]
-pub fn set_pixel_rgb_32(fb: &mut Framebuffer, x: u32, y: u32, rgb: [u8; 3]) {
-    let addr = (fb.var_info.xoffset as isize + x as isize) * (fb.bytes_per_pixel as isize) +
-               (fb.var_info.yoffset as isize + y as isize) * (fb.fix_info.line_length as isize);
+// The pixel value of a gray level, according to the bitfields of the frame.
+fn encode_gray(var_info: &VarScreenInfo, gray: u8) -> u32 {
+    let mut value = channels(var_info).iter().fold(0, |value, field| {
+        let max = (1u64 << field.length) - 1;
+        value | (((gray as u64 * max + 127) / 255) as u32) << field.offset
+    });
+    // Keep the pixels opaque.
+    if var_info.transp.length > 0 {
+        value |= (((1u64 << var_info.transp.length) - 1) as u32) << var_info.transp.offset;
+    }
+    value
+}
 
-    assert!(addr < fb.frame_size as isize);
+// The gray level of a pixel value: the mean of its channels, as given by `channels`.
+fn decode_gray(fields: &[Bitfield], value: u32) -> u8 {
+    let sum = fields.iter().fold(0, |sum, field| {
+        let max = (1u64 << field.length) - 1;
+        let level = (value as u64 >> field.offset) & max;
+        sum + (level * 255 + max / 2).checked_div(max).unwrap_or(0)
+    });
+    (sum / fields.len() as u64) as u8
+}
 
-    unsafe {
-        let spot = fb.frame.offset(addr) as *mut u8;
-        *spot.offset(0) = rgb[2];
-        *spot.offset(1) = rgb[1];
-        *spot.offset(2) = rgb[0];
-        // *spot.offset(3) = 0x00;
+// Copy the columns `x_min..x_max` of row `y` of the shadow buffer to the frame.
+fn copy_row_8(fb: &mut Framebuffer, y: u32, x_min: u32, x_max: u32) {
+    let start = (fb.var_info.xoffset + x_min) as usize +
+                (fb.var_info.yoffset + y) as usize * fb.fix_info.line_length as usize;
+    let end = start + (x_max - x_min) as usize;
+    let frame = unsafe { slice::from_raw_parts_mut(fb.frame as *mut u8, fb.frame_size) };
+    let row = (y * fb.var_info.xres) as usize;
+    let grays = &fb.shadow[row + x_min as usize..row + x_max as usize];
+    for (spot, &gray) in frame[start..end].iter_mut().zip(grays) {
+        *spot = fb.palette[gray as usize] as u8;
     }
 }
 
-fn as_rgb_16(fb: &Framebuffer) -> Vec<u8> {
-    let (width, height) = fb.dims();
-    let mut rgb888 = Vec::with_capacity((width * height * 3) as usize);
-    let rgb565 = fb.as_bytes();
-    let virtual_width = fb.var_info.xres_virtual as usize;
-    for (_, pair) in rgb565.chunks(2).take(height as usize * virtual_width).enumerate()
-                           .filter(|&(i, _)| i % virtual_width < width as usize) {
-        let r = pair[1] & 0b11111000;
-        let g = ((pair[1] & 0b00000111) << 5) | ((pair[0] & 0b11100000) >> 3);
-        let b = (pair[0] & 0b00011111) << 3;
-        rgb888.extend_from_slice(&[r, g, b]);
-    }
-    rgb888
+fn copy_row_16(fb: &mut Framebuffer, y: u32, x_min: u32, x_max: u32) {
+    let start = (fb.var_info.xoffset + x_min) as usize * 2 +
+                (fb.var_info.yoffset + y) as usize * fb.fix_info.line_length as usize;
+    let end = start + (x_max - x_min) as usize * 2;
+    let frame = unsafe { slice::from_raw_parts_mut(fb.frame as *mut u8, fb.frame_size) };
+    let row = (y * fb.var_info.xres) as usize;
+    let grays = &fb.shadow[row + x_min as usize..row + x_max as usize];
+    for (spot, &gray) in frame[start..end].chunks_mut(2).zip(grays) {
+        let value = fb.palette[gray as usize];
+        spot[0] = value as u8;
+        spot[1] = (value >> 8) as u8;
+    }
 }
 
-fn as_rgb_32(fb: &Framebuffer) -> Vec<u8> {
-    let (width, height) = fb.dims();
-    let mut rgb888 = Vec::with_capacity((width * height * 3) as usize);
-    let bgra8888 = fb.as_bytes();
-    let virtual_width = fb.var_info.xres_virtual as usize;
-    for (_, bgra) in bgra8888.chunks(4).take(height as usize * virtual_width).enumerate()
-                           .filter(|&(i, _)| i % virtual_width < width as usize) {
-        let r = bgra[2];
-        let g = bgra[1];
-        let b = bgra[0];
-        rgb888.extend_from_slice(&[r, g, b]);
-    }
-    rgb888
+// Also used for 24 bits frames.
+fn copy_row_32(fb: &mut Framebuffer, y: u32, x_min: u32, x_max: u32) {
+    let bytes_per_pixel = fb.bytes_per_pixel as usize;
+    let start = (fb.var_info.xoffset + x_min) as usize * bytes_per_pixel +
+                (fb.var_info.yoffset + y) as usize * fb.fix_info.line_length as usize;
+    let end = start + (x_max - x_min) as usize * bytes_per_pixel;
+    let frame = unsafe { slice::from_raw_parts_mut(fb.frame as *mut u8, fb.frame_size) };
+    let row = (y * fb.var_info.xres) as usize;
+    let grays = &fb.shadow[row + x_min as usize..row + x_max as usize];
+    for (spot, &gray) in frame[start..end].chunks_mut(bytes_per_pixel).zip(grays) {
+        let value = fb.palette[gray as usize];
+        for (i, byte) in spot.iter_mut().enumerate() {
+            *byte = (value >> (8 * i)) as u8;
+        }
+    }
 }
 
 pub fn fix_screen_info(device: &File) -> io::Result<FixScreenInfo> {
@@ -473,3 +549,65 @@ impl Drop for Framebuffer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{VarScreenInfo, Bitfield, check_format, channels, encode_gray, decode_gray};
+
+    fn var_info(bits_per_pixel: u32, fields: [(u32, u32); 4]) -> VarScreenInfo {
+        let field = |(offset, length)| Bitfield { offset, length, msb_right: 0 };
+        VarScreenInfo {
+            bits_per_pixel,
+            red: field(fields[0]),
+            green: field(fields[1]),
+            blue: field(fields[2]),
+            transp: field(fields[3]),
+            .. VarScreenInfo::default()
+        }
+    }
+
+    #[test]
+    fn gray_levels_of_8_bits_frames() {
+        let mut info = var_info(8, [(0, 0); 4]);
+        info.grayscale = 1;
+        for gray in 0..256 {
+            assert_eq!(encode_gray(&info, gray as u8), gray);
+            assert_eq!(decode_gray(&channels(&info), gray), gray as u8);
+        }
+    }
+
+    #[test]
+    fn gray_levels_of_rgb565_frames() {
+        let info = var_info(16, [(11, 5), (5, 6), (0, 5), (0, 0)]);
+        assert_eq!(encode_gray(&info, 0x00), 0x0000);
+        assert_eq!(encode_gray(&info, 0xff), 0xffff);
+        for gray in 0..256 {
+            let decoded = decode_gray(&channels(&info), encode_gray(&info, gray as u8));
+            assert!((decoded as i32 - gray).abs() <= 4, "{} -> {}", gray, decoded);
+        }
+    }
+
+    #[test]
+    fn pixels_of_32_bits_frames_are_opaque() {
+        let info = var_info(32, [(16, 8), (8, 8), (0, 8), (24, 8)]);
+        assert_eq!(encode_gray(&info, 0x00), 0xff00_0000);
+        assert_eq!(encode_gray(&info, 0x80), 0xff80_8080);
+        assert_eq!(decode_gray(&channels(&info), 0xff80_8080), 0x80);
+        // The channels are averaged.
+        assert_eq!(decode_gray(&channels(&info), 0x00ff_0000), 0x55);
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected() {
+        let mut info = var_info(16, [(11, 5), (5, 6), (0, 5), (0, 0)]);
+        assert!(check_format(&info).is_ok());
+        info.green.msb_right = 1;
+        assert!(check_format(&info).is_err());
+        let mut info = var_info(8, [(0, 0); 4]);
+        info.grayscale = 1;
+        assert!(check_format(&info).is_ok());
+        // The FOURCC code of the Y8 format.
+        info.grayscale = 0x2020_3859;
+        assert!(check_format(&info).is_err());
+    }
+}
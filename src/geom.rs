@@ -1,7 +1,7 @@
 use std::cmp;
 use std::ops::{Add, AddAssign, Sub, SubAssign, Mul, MulAssign, Div, DivAssign};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum Dir {
     North,
     East,
@@ -89,6 +89,35 @@ macro_rules! pt {
     ($a:expr) => ($crate::geom::Point::new($a, $a));
 }
 
+// Distance between the point `p` and the segment `[a, b]`.
+pub fn point_segment_distance(p: &Point, a: &Point, b: &Point) -> f32 {
+    let ab = *b - *a;
+    let ap = *p - *a;
+    let len2 = (ab.x * ab.x + ab.y * ab.y) as f32;
+    if len2 == 0.0 {
+        return ap.length();
+    }
+    let t = ((ap.x * ab.x + ap.y * ab.y) as f32 / len2).clamp(0.0, 1.0);
+    let (dx, dy) = (ap.x as f32 - t * ab.x as f32, ap.y as f32 - t * ab.y as f32);
+    (dx * dx + dy * dy).sqrt()
+}
+
+// Distance between the segments `[a, b]` and `[c, d]`.
+pub fn segments_distance(a: &Point, b: &Point, c: &Point, d: &Point) -> f32 {
+    let cross = |o: &Point, p: &Point, q: &Point| {
+        (p.x - o.x) as i64 * (q.y - o.y) as i64 - (p.y - o.y) as i64 * (q.x - o.x) as i64
+    };
+    let (d1, d2) = (cross(a, b, c), cross(a, b, d));
+    let (d3, d4) = (cross(c, d, a), cross(c, d, b));
+    if ((d1 > 0 && d2 < 0) || (d1 < 0 && d2 > 0)) &&
+       ((d3 > 0 && d4 < 0) || (d3 < 0 && d4 > 0)) {
+        return 0.0;
+    }
+    point_segment_distance(a, c, d).min(point_segment_distance(b, c, d))
+                                   .min(point_segment_distance(c, a, b))
+                                   .min(point_segment_distance(d, a, b))
+}
+
 // Based on https://golang.org/pkg/image/#Rectangle
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct Rectangle {
@@ -57,7 +57,7 @@ pub fn gesture_events(rx: Receiver<DeviceEvent>) -> Receiver<GestureEvent> {
 }
 
 pub fn parse_gesture_events(rx: Receiver<DeviceEvent>, ty: Sender<GestureEvent>) {
-    let mut contacts: Arc<Mutex<HashMap<i32, TouchState>>> = Arc::new(Mutex::new(HashMap::new()));
+    let contacts: Arc<Mutex<HashMap<i32, TouchState>>> = Arc::new(Mutex::new(HashMap::new()));
     let mut segments: Vec<(Point, Point)> = Vec::new();
     let mut timeouts: HashMap<i32, Sender<()>> = HashMap::new();
     let dpi = Device::current().dpi;
@@ -155,7 +155,36 @@ pub fn parse_gesture_events(rx: Receiver<DeviceEvent>, ty: Sender<GestureEvent>)
                             _ => (),
                         }
                     } else {
-                        segments.clear();
+                        // All the fingers must agree on the kind of gesture.
+                        let gestures: Vec<GestureEvent> = segments.drain(..).map(|sg| interpret_segment(sg, dpi)).collect();
+                        let taps: Vec<Point> = gestures.iter().filter_map(|ge| {
+                            match *ge {
+                                GestureEvent::Tap { center, .. } => Some(center),
+                                _ => None,
+                            }
+                        }).collect();
+                        let swipes: Vec<(Dir, Point, Point)> = gestures.iter().filter_map(|ge| {
+                            match *ge {
+                                GestureEvent::Swipe { dir, start, end, .. } => Some((dir, start, end)),
+                                _ => None,
+                            }
+                        }).collect();
+                        if taps.len() == len {
+                            let sum = taps.iter().fold(Point::default(), |sum, &c| sum + c);
+                            ty.send(GestureEvent::Tap {
+                                center: sum / len as i32,
+                                fingers_count: len,
+                            }).unwrap();
+                        } else if swipes.len() == len && swipes.iter().all(|sw| sw.0 == swipes[0].0) {
+                            let start = swipes.iter().fold(Point::default(), |sum, sw| sum + sw.1);
+                            let end = swipes.iter().fold(Point::default(), |sum, sw| sum + sw.2);
+                            ty.send(GestureEvent::Swipe {
+                                dir: swipes[0].0,
+                                start: start / len as i32,
+                                end: end / len as i32,
+                                fingers_count: len,
+                            }).unwrap();
+                        }
                     }
                 }
             },
@@ -181,3 +210,35 @@ fn interpret_segment((a, b): (Point, Point), dpi: u16) -> GestureEvent {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use input::{DeviceEvent, FingerStatus};
+    use geom::Dir;
+    use super::{gesture_events, GestureEvent};
+
+    fn finger(status: FingerStatus, id: i32, x: i32, y: i32) -> DeviceEvent {
+        DeviceEvent::Finger { time: 0.0, id, status, position: pt!(x, y), pressure: 1.0 }
+    }
+
+    #[test]
+    fn three_fingers_swipe() {
+        let (tx, rx) = mpsc::channel();
+        let events = gesture_events(rx);
+        for id in 0..3 {
+            tx.send(finger(FingerStatus::Down, id, 100 + 50 * id, 500)).unwrap();
+        }
+        for id in 0..3 {
+            tx.send(finger(FingerStatus::Up, id, 100 + 50 * id, 200)).unwrap();
+        }
+        drop(tx);
+        let swipes: Vec<(Dir, usize)> = events.iter().filter_map(|evt| {
+            match evt {
+                GestureEvent::Swipe { dir, fingers_count, .. } => Some((dir, fingers_count)),
+                _ => None,
+            }
+        }).collect();
+        assert_eq!(swipes, vec![(Dir::North, 3)]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::Path;
+use std::io;
+use display::{Display, Mode};
+use framebuffer::{update_params, save_gray, save_rgb};
+use geom::Rectangle;
+
+// A call made to the refresh API of a display.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Call {
+    Update {
+        region: Rectangle,
+        mode: Mode,
+        update_mode: u32,
+        waveform_mode: u32,
+        marker: u32,
+    },
+    Wait(u32),
+}
+
+// An in-memory grayscale display.
+// Every update and wait request is recorded in `log`, in call order.
+pub struct HeadlessDisplay {
+    width: u32,
+    height: u32,
+    token: u32,
+    pub pixels: Vec<u8>,
+    pub inverted: bool,
+    // Whether the pixels are saved as an RGB image, like the frames of a color framebuffer.
+    pub color: bool,
+    pub log: Vec<Call>,
+}
+
+impl HeadlessDisplay {
+    pub fn new(width: u32, height: u32) -> HeadlessDisplay {
+        HeadlessDisplay {
+            width,
+            height,
+            token: 1,
+            pixels: vec![0xff; (width * height) as usize],
+            inverted: false,
+            color: false,
+            log: Vec::new(),
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> u8 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    // The regions that were refreshed, in call order.
+    #[cfg(test)]
+    pub fn updates(&self) -> Vec<(Rectangle, Mode)> {
+        self.log.iter().filter_map(|call| {
+            match *call {
+                Call::Update { region, mode, .. } => Some((region, mode)),
+                _ => None,
+            }
+        }).collect()
+    }
+}
+
+impl Display for HeadlessDisplay {
+    // Pixels outside of the display are ignored.
+    fn set_pixel(&mut self, x: u32, y: u32, gray: u8) {
+        if x < self.width && y < self.height {
+            self.pixels[(y * self.width + x) as usize] = gray;
+        }
+    }
+
+    fn draw_span(&mut self, y: u32, x_min: u32, x_max: u32, gray: u8) {
+        let x_max = x_max.min(self.width);
+        if y < self.height && x_min < x_max {
+            let row = (y * self.width) as usize;
+            for pixel in &mut self.pixels[row + x_min as usize..row + x_max as usize] {
+                *pixel = gray;
+            }
+        }
+    }
+
+    // Pixels outside of the display are white.
+    fn get_pixel(&self, x: u32, y: u32) -> u8 {
+        if x < self.width && y < self.height {
+            self.pixel(x, y)
+        } else {
+            0xff
+        }
+    }
+
+    fn update(&mut self, rect: Rectangle, mode: Mode) -> io::Result<u32> {
+        let (update_mode, waveform_mode) = update_params(mode);
+        let marker = self.token;
+        self.log.push(Call::Update {
+            region: rect,
+            mode,
+            update_mode,
+            waveform_mode,
+            marker,
+        });
+        self.token = self.token.wrapping_add(1);
+        Ok(marker)
+    }
+
+    fn wait(&mut self, token: u32) -> io::Result<i32> {
+        self.log.push(Call::Wait(token));
+        Ok(0)
+    }
+
+    fn dims(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn toggle_inverse(&mut self) {
+        self.inverted = !self.inverted;
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if self.color {
+            save_rgb(path, self.dims(), &self.pixels)
+        } else {
+            save_gray(path, self.dims(), &self.pixels)
+        }
+    }
+
+    fn is_color(&self) -> bool {
+        self.color
+    }
+}
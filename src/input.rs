@@ -3,15 +3,24 @@ extern crate libc;
 use std::sync::mpsc::{self, Sender, Receiver};
 use std::os::unix::io::AsRawFd;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::io::Read;
+use std::time::Duration;
+use std::io::{self, Read, Write, BufRead, BufReader, BufWriter};
 use std::fs::File;
+use std::path::Path;
 use std::slice;
 use std::mem;
 use std::env;
 use device::Device;
+use calibration::{Transform, calibration_path};
 use geom::Point;
 
+const EVIOCGABS_BASE: libc::c_ulong = 0x80184540;
+
+// Start of the header of the recordings.
+const RECORDING_MAGIC: &[u8] = b"SKETCHREC1";
+
 // Event types
 pub const EV_SYN: u16 = 0;
 pub const EV_KEY: u16 = 1;
@@ -20,6 +29,7 @@ pub const EV_ABS: u16 = 3;
 // Event codes
 pub const SYN_MT_REPORT: u16 = 2;
 pub const SYN_REPORT: u16 = 0;
+pub const ABS_MT_SLOT: u16 = 47;
 pub const ABS_MT_TRACKING_ID: u16 = 57;
 pub const ABS_MT_TOUCH_MAJOR: u16 = 48;
 pub const ABS_PRESSURE: u16 = 24;
@@ -37,14 +47,22 @@ pub const SINGLE_TOUCH_CODES: TouchCodes = TouchCodes {
     y: ABS_Y,
 };
 
-pub const MULTI_TOUCH_CODES: TouchCodes = TouchCodes {
+pub const MULTI_TOUCH_CODES_A: TouchCodes = TouchCodes {
     report: SYN_MT_REPORT,
     pressure: ABS_MT_TOUCH_MAJOR,
     x: ABS_MT_POSITION_X,
     y: ABS_MT_POSITION_Y,
 };
 
+pub const MULTI_TOUCH_CODES_B: TouchCodes = TouchCodes {
+    report: SYN_REPORT,
+    pressure: ABS_MT_TOUCH_MAJOR,
+    x: ABS_MT_POSITION_X,
+    y: ABS_MT_POSITION_Y,
+};
+
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct InputEvent {
     pub time: libc::timeval,
     pub kind: u16, // type
@@ -52,8 +70,20 @@ pub struct InputEvent {
     pub value: i32,
 }
 
+// Range of an absolute axis
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct AbsInfo {
+    pub value: i32,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
 // Handle different touch protocols
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct TouchCodes {
     report: u16,
     pressure: u16,
@@ -64,10 +94,11 @@ pub struct TouchCodes {
 #[derive(Debug, Eq, PartialEq)]
 pub enum TouchProto {
     Single,
-    Multi,
+    MultiA,
+    MultiB,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum FingerStatus {
     Down,
     Motion,
@@ -92,6 +123,8 @@ pub enum DeviceEvent {
         id: i32,
         status: FingerStatus,
         position: Point,
+        // Normalized to `0.0..1.0`.
+        pressure: f32,
     },
     Button {
         time: f64,
@@ -107,15 +140,97 @@ pub fn seconds(time: libc::timeval) -> f64 {
 pub struct Input {
     pub events: Receiver<DeviceEvent>,
     pub dims: (u32, u32),
+    // Maps the raw touch coordinates to screen coordinates.
+    pub transform: Arc<Mutex<Transform>>,
 }
 
 impl Input {
-    pub fn new(paths: Vec<String>, dims: (u32, u32)) -> Input {
-        let events = device_events(raw_events(paths), dims);
-        Input {
-            events: events,
-            dims: dims,
+    // The raw events can be replayed from the file given by `SKETCH_REPLAY`,
+    // at the speed factor given by `SKETCH_REPLAY_SPEED` (zero means as fast as possible),
+    // and recorded into the file given by `SKETCH_RECORD`.
+    pub fn new(paths: Vec<String>, dims: (u32, u32)) -> io::Result<Input> {
+        let mut recorded_transform = None;
+        let (mut raw, ranges) = if let Ok(path) = env::var("SKETCH_REPLAY") {
+            let speed = env::var("SKETCH_REPLAY_SPEED").ok()
+                                                       .and_then(|v| v.parse().ok())
+                                                       .unwrap_or(1.0);
+            let (raw, header) = replay_events(path, speed)?;
+            if let Some(header) = header {
+                // Behave like the recording device.
+                env::set_var("PRODUCT", &header.product);
+                recorded_transform = Some(header.transform);
+                (raw, header.ranges)
+            } else {
+                (raw, HashMap::new())
+            }
+        } else {
+            let ranges = abs_ranges(&paths);
+            (raw_events(paths), ranges)
+        };
+        let transform = recorded_transform.unwrap_or_else(|| {
+            Transform::load(calibration_path()).unwrap_or_else(|_| {
+                let tc = touch_codes(&Device::current().proto);
+                Transform::from_ranges(ranges.get(&tc.x), ranges.get(&tc.y), dims)
+            })
+        });
+        if let Ok(path) = env::var("SKETCH_RECORD") {
+            let header = RecordingHeader {
+                product: env::var("PRODUCT").unwrap_or_default(),
+                transform,
+                ranges: ranges.clone(),
+            };
+            raw = record_events(raw, path, &header)?;
         }
+        let transform = Arc::new(Mutex::new(transform));
+        let events = device_events(raw, ranges, transform.clone());
+        Ok(Input {
+            events,
+            dims,
+            transform,
+        })
+    }
+}
+
+pub fn abs_info(file: &File, code: u16) -> io::Result<AbsInfo> {
+    let mut info = AbsInfo::default();
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGABS_BASE + code as libc::c_ulong, &mut info) };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(info),
+    }
+}
+
+// Query the ranges of the touch axes, across all the given devices.
+pub fn abs_ranges(paths: &[String]) -> HashMap<u16, AbsInfo> {
+    let mut ranges = HashMap::new();
+    for path in paths {
+        if let Ok(file) = File::open(path) {
+            for &code in &[ABS_X, ABS_Y, ABS_PRESSURE,
+                           ABS_MT_POSITION_X, ABS_MT_POSITION_Y, ABS_MT_TOUCH_MAJOR] {
+                if ranges.contains_key(&code) {
+                    continue;
+                }
+                if let Ok(info) = abs_info(&file, code) {
+                    if info.maximum > info.minimum {
+                        ranges.insert(code, info);
+                    }
+                }
+            }
+        }
+    }
+    ranges
+}
+
+// Map a raw pressure value to `0.0..1.0`.
+// If the range of the pressure axis is unknown, or too narrow to be meaningful,
+// any contact is considered as a full pressure.
+pub fn normalize_pressure(value: i32, range: Option<&AbsInfo>) -> f32 {
+    match range {
+        Some(info) if info.maximum - info.minimum > 1 => {
+            let p = (value - info.minimum) as f32 / (info.maximum - info.minimum) as f32;
+            p.clamp(0.0, 1.0)
+        },
+        _ => 1.0,
     }
 }
 
@@ -145,58 +260,262 @@ pub fn parse_raw_events(paths: Vec<String>, tx: Sender<InputEvent>) {
         }
         for (pfd, mut file) in pfds.iter().zip(&files) {
             if pfd.revents & libc::POLLIN != 0 {
-                let mut input_event: InputEvent = unsafe { mem::uninitialized() };
-                unsafe {
-                    let event_slice = slice::from_raw_parts_mut(&mut input_event as *mut InputEvent as *mut u8,
-                                                                mem::size_of::<InputEvent>());
-                    if file.read_exact(event_slice).is_err() {
-                        break;
-                    }
+                if let Ok(input_event) = read_event(&mut file) {
+                    tx.send(input_event).unwrap();
+                } else {
+                    break;
                 }
-                tx.send(input_event).unwrap();
             }
         }
     }
 }
 
-pub fn device_events(rx: Receiver<InputEvent>, dims: (u32, u32)) -> Receiver<DeviceEvent> {
+// The devices produce events in the native format of the machine.
+pub fn read_event<R: Read>(reader: &mut R) -> io::Result<InputEvent> {
+    let mut input_event: InputEvent = unsafe { mem::zeroed() };
+    unsafe {
+        let event_slice = slice::from_raw_parts_mut(&mut input_event as *mut InputEvent as *mut u8,
+                                                    mem::size_of::<InputEvent>());
+        reader.read_exact(event_slice)?;
+    }
+    Ok(input_event)
+}
+
+// What replaying a recording needs to know about the device it was made on.
+#[derive(Debug, Clone)]
+pub struct RecordingHeader {
+    // The value of `PRODUCT`.
+    pub product: String,
+    pub transform: Transform,
+    pub ranges: HashMap<u16, AbsInfo>,
+}
+
+// Recordings can be replayed on other machines: every field is stored with a fixed width,
+// in little-endian order. The header is the magic number, the product name (u8 length and bytes),
+// the six coefficients of the transform (f32), the number of axes (u16), and the code (u16)
+// and range (six i32, as in `AbsInfo`) of each axis.
+// Returns `None` for a plain kernel dump, which has no header.
+pub fn read_header<R: BufRead>(reader: &mut R) -> io::Result<Option<RecordingHeader>> {
+    if !reader.fill_buf()?.starts_with(RECORDING_MAGIC) {
+        return Ok(None);
+    }
+    reader.consume(RECORDING_MAGIC.len());
+    let mut product = vec![0; read_u8(reader)? as usize];
+    reader.read_exact(&mut product)?;
+    let product = String::from_utf8(product).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut coefs = [0.0f32; 6];
+    for coef in &mut coefs {
+        *coef = f32::from_bits(read_u32(reader)?);
+    }
+    let transform = Transform {
+        a: coefs[0], b: coefs[1], c: coefs[2],
+        d: coefs[3], e: coefs[4], f: coefs[5],
+    };
+    let mut ranges = HashMap::new();
+    for _ in 0..read_u16(reader)? {
+        let code = read_u16(reader)?;
+        let info = AbsInfo {
+            value: read_u32(reader)? as i32,
+            minimum: read_u32(reader)? as i32,
+            maximum: read_u32(reader)? as i32,
+            fuzz: read_u32(reader)? as i32,
+            flat: read_u32(reader)? as i32,
+            resolution: read_u32(reader)? as i32,
+        };
+        ranges.insert(code, info);
+    }
+    Ok(Some(RecordingHeader {
+        product,
+        transform,
+        ranges,
+    }))
+}
+
+pub fn write_header<W: Write>(writer: &mut W, header: &RecordingHeader) -> io::Result<()> {
+    writer.write_all(RECORDING_MAGIC)?;
+    let product = header.product.as_bytes();
+    let product = &product[..product.len().min(255)];
+    writer.write_all(&[product.len() as u8])?;
+    writer.write_all(product)?;
+    let t = &header.transform;
+    for coef in &[t.a, t.b, t.c, t.d, t.e, t.f] {
+        writer.write_all(&coef.to_bits().to_le_bytes())?;
+    }
+    writer.write_all(&(header.ranges.len() as u16).to_le_bytes())?;
+    let mut codes: Vec<&u16> = header.ranges.keys().collect();
+    codes.sort();
+    for code in codes {
+        let info = &header.ranges[code];
+        writer.write_all(&code.to_le_bytes())?;
+        for value in &[info.value, info.minimum, info.maximum, info.fuzz, info.flat, info.resolution] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// The events of a recording are the seconds (i64) and microseconds (i32) of their time,
+// followed by their type (u16), code (u16) and value (i32).
+pub fn read_recorded_event<R: Read>(reader: &mut R) -> io::Result<InputEvent> {
+    let mut buf = [0; 20];
+    reader.read_exact(&mut buf)?;
+    let mut input_event: InputEvent = unsafe { mem::zeroed() };
+    input_event.time.tv_sec = i64::from_le_bytes([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]]) as libc::time_t;
+    input_event.time.tv_usec = i32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]) as libc::suseconds_t;
+    input_event.kind = u16::from_le_bytes([buf[12], buf[13]]);
+    input_event.code = u16::from_le_bytes([buf[14], buf[15]]);
+    input_event.value = i32::from_le_bytes([buf[16], buf[17], buf[18], buf[19]]);
+    Ok(input_event)
+}
+
+pub fn write_recorded_event<W: Write>(writer: &mut W, input_event: &InputEvent) -> io::Result<()> {
+    // The size of `time_t` depends on the architecture.
+    let sec: i64 = input_event.time.tv_sec as _;
+    writer.write_all(&sec.to_le_bytes())?;
+    writer.write_all(&(input_event.time.tv_usec as i32).to_le_bytes())?;
+    writer.write_all(&input_event.kind.to_le_bytes())?;
+    writer.write_all(&input_event.code.to_le_bytes())?;
+    writer.write_all(&input_event.value.to_le_bytes())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+// Forward the events of `rx` while appending them to the file at `path`, after the given header.
+pub fn record_events<P: AsRef<Path>>(rx: Receiver<InputEvent>, path: P, header: &RecordingHeader) -> io::Result<Receiver<InputEvent>> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    write_header(&mut writer, header)?;
+    writer.flush()?;
+    let (tx, ry) = mpsc::channel();
+    thread::spawn(move || save_raw_events(rx, tx, writer));
+    Ok(ry)
+}
+
+pub fn save_raw_events(rx: Receiver<InputEvent>, tx: Sender<InputEvent>, mut writer: BufWriter<File>) {
+    while let Ok(evt) = rx.recv() {
+        write_recorded_event(&mut writer, &evt).unwrap();
+        // Keep the recording usable if sketch doesn't exit cleanly.
+        if evt.kind == EV_SYN {
+            writer.flush().unwrap();
+        }
+        if tx.send(evt).is_err() {
+            break;
+        }
+    }
+}
+
+// Feed the events recorded in the file at `path`, honoring the original delays divided by `speed`.
+// Returns the events and the header of the recording.
+pub fn replay_events<P: AsRef<Path>>(path: P, speed: f64) -> io::Result<(Receiver<InputEvent>, Option<RecordingHeader>)> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+    let header = read_header(&mut reader)?;
+    let (tx, rx) = mpsc::channel();
+    let native = header.is_none();
+    thread::spawn(move || load_raw_events(reader, native, speed, tx));
+    Ok((rx, header))
+}
+
+// The events of a plain kernel dump are in the `native` format.
+pub fn load_raw_events(mut reader: BufReader<File>, native: bool, speed: f64, tx: Sender<InputEvent>) {
+    let mut last_time: Option<f64> = None;
+    loop {
+        let evt = if native {
+            read_event(&mut reader)
+        } else {
+            read_recorded_event(&mut reader)
+        };
+        let evt = match evt {
+            Ok(evt) => evt,
+            Err(_) => break,
+        };
+        let time = seconds(evt.time);
+        if let Some(last_time) = last_time {
+            let delay = (time - last_time) / speed;
+            if speed > 0.0 && delay > 0.0 {
+                thread::sleep(Duration::new(delay.trunc() as u64,
+                                            (delay.fract() * 1e9) as u32));
+            }
+        }
+        last_time = Some(time);
+        if tx.send(evt).is_err() {
+            break;
+        }
+    }
+}
+
+pub fn device_events(rx: Receiver<InputEvent>, ranges: HashMap<u16, AbsInfo>, transform: Arc<Mutex<Transform>>) -> Receiver<DeviceEvent> {
     let (ty, ry) = mpsc::channel();
-    thread::spawn(move || parse_device_events(rx, ty, dims));
+    thread::spawn(move || parse_device_events(rx, ty, ranges, transform));
     ry
 }
 
-pub fn parse_device_events(rx: Receiver<InputEvent>, ty: Sender<DeviceEvent>, dims: (u32, u32)) {
+pub fn touch_codes(proto: &TouchProto) -> TouchCodes {
+    match *proto {
+        TouchProto::Single => SINGLE_TOUCH_CODES,
+        TouchProto::MultiA => MULTI_TOUCH_CODES_A,
+        TouchProto::MultiB => MULTI_TOUCH_CODES_B,
+    }
+}
+
+pub fn parse_device_events(rx: Receiver<InputEvent>, ty: Sender<DeviceEvent>, ranges: HashMap<u16, AbsInfo>, transform: Arc<Mutex<Transform>>) {
+    let device = Device::current();
+    let tc = touch_codes(&device.proto);
+    if device.proto == TouchProto::MultiB {
+        parse_slot_events(rx, ty, ranges, transform, tc);
+    } else {
+        parse_contact_events(rx, ty, ranges, transform, tc);
+    }
+}
+
+// Single touch and multi-touch protocol A: the contacts are reported one after the other.
+fn parse_contact_events(rx: Receiver<InputEvent>, ty: Sender<DeviceEvent>, ranges: HashMap<u16, AbsInfo>, transform: Arc<Mutex<Transform>>, tc: TouchCodes) {
     let mut id = 0;
-    let mut position = Point::default();
+    let mut raw = Point::default();
     let mut pressure = 0;
     let mut fingers: HashMap<i32, Point> = HashMap::new();
-    let device = Device::current();
-    let mut tc = if device.proto == TouchProto::Multi { MULTI_TOUCH_CODES } else { SINGLE_TOUCH_CODES };
-    if env::var("SKETCH_UNSWAP_XY").is_err() {
-        mem::swap(&mut tc.x, &mut tc.y);
-    }
-    let mirror_x = env::var("SKETCH_UNMIRROR_X").is_err();
+    let pressure_range = ranges.get(&tc.pressure);
+    let min_pressure = pressure_range.map_or(0, |info| info.minimum);
     while let Ok(evt) = rx.recv() {
         if evt.kind == EV_ABS {
             if evt.code == tc.pressure {
                 pressure = evt.value;
             } else if evt.code == tc.x {
-                position.x = if mirror_x { dims.0 as i32 - 1 - evt.value } else { evt.value };
+                raw.x = evt.value;
             } else if evt.code == tc.y {
-                position.y = evt.value;
+                raw.y = evt.value;
             } else if evt.code == ABS_MT_TRACKING_ID {
                 id = evt.value;
             }
         } else if evt.kind == EV_SYN {
             if evt.code == tc.report {
+                let position = transform.lock().unwrap().apply(&raw);
+                let normalized_pressure = normalize_pressure(pressure, pressure_range);
                 if let Some(&p) = fingers.get(&id) {
-                    if pressure > 0 {
+                    if pressure > min_pressure {
                         if p != position {
                             ty.send(DeviceEvent::Finger {
                                 time: seconds(evt.time),
                                 id: id,
                                 status: FingerStatus::Motion,
                                 position: position,
+                                pressure: normalized_pressure,
                             }).unwrap();
                         }
                     } else {
@@ -205,6 +524,7 @@ pub fn parse_device_events(rx: Receiver<InputEvent>, ty: Sender<DeviceEvent>, di
                             id: id,
                             status: FingerStatus::Up,
                             position: position,
+                            pressure: 0.0,
                         }).unwrap();
                         fingers.remove(&id);
                     }
@@ -214,19 +534,222 @@ pub fn parse_device_events(rx: Receiver<InputEvent>, ty: Sender<DeviceEvent>, di
                         id: id,
                         status: FingerStatus::Down,
                         position: position,
+                        pressure: normalized_pressure,
                     }).unwrap();
                     fingers.insert(id, position);
                 }
             }
-        } else if evt.kind == EV_KEY {
-            if evt.code == KEY_POWER {
-                ty.send(DeviceEvent::Button {
-                    time: seconds(evt.time),
-                    code: ButtonCode::Power,
-                    status: if evt.value == 1 { ButtonStatus::Pressed } else
-                                              { ButtonStatus::Released },
-                }).unwrap();
+        } else if let Some(button_event) = button_event(&evt) {
+            ty.send(button_event).unwrap();
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    // Tracking ID of the contact, -1 if the slot is unused.
+    id: i32,
+    // Tracking ID of the contact as of the last report.
+    reported_id: i32,
+    raw: Point,
+    pressure: i32,
+}
+
+impl Default for Slot {
+    fn default() -> Slot {
+        Slot {
+            id: -1,
+            reported_id: -1,
+            raw: Point::default(),
+            pressure: 0,
+        }
+    }
+}
+
+// Multi-touch protocol B: the contacts are stored in slots and only the changes are reported.
+fn parse_slot_events(rx: Receiver<InputEvent>, ty: Sender<DeviceEvent>, ranges: HashMap<u16, AbsInfo>, transform: Arc<Mutex<Transform>>, tc: TouchCodes) {
+    let mut slot = 0;
+    let mut slots: HashMap<i32, Slot> = HashMap::new();
+    let mut fingers: HashMap<i32, Point> = HashMap::new();
+    let pressure_range = ranges.get(&tc.pressure);
+    while let Ok(evt) = rx.recv() {
+        if evt.kind == EV_ABS {
+            if evt.code == ABS_MT_SLOT {
+                slot = evt.value;
+            } else if evt.code == ABS_MT_TRACKING_ID {
+                slots.entry(slot).or_default().id = evt.value;
+            } else if evt.code == tc.x {
+                slots.entry(slot).or_default().raw.x = evt.value;
+            } else if evt.code == tc.y {
+                slots.entry(slot).or_default().raw.y = evt.value;
+            } else if evt.code == tc.pressure {
+                slots.entry(slot).or_default().pressure = evt.value;
             }
+        } else if evt.kind == EV_SYN {
+            if evt.code == tc.report {
+                let time = seconds(evt.time);
+                let transform = transform.lock().unwrap();
+                for sl in slots.values_mut() {
+                    let position = transform.apply(&sl.raw);
+                    let pressure = normalize_pressure(sl.pressure, pressure_range);
+                    if sl.reported_id != -1 && sl.reported_id != sl.id {
+                        let position = fingers.remove(&sl.reported_id).unwrap_or(position);
+                        ty.send(DeviceEvent::Finger {
+                            time,
+                            id: sl.reported_id,
+                            status: FingerStatus::Up,
+                            position,
+                            pressure: 0.0,
+                        }).unwrap();
+                    }
+                    if sl.id != -1 {
+                        if sl.reported_id != sl.id {
+                            ty.send(DeviceEvent::Finger {
+                                time,
+                                id: sl.id,
+                                status: FingerStatus::Down,
+                                position,
+                                pressure,
+                            }).unwrap();
+                            fingers.insert(sl.id, position);
+                        } else if fingers.get(&sl.id) != Some(&position) {
+                            ty.send(DeviceEvent::Finger {
+                                time,
+                                id: sl.id,
+                                status: FingerStatus::Motion,
+                                position,
+                                pressure,
+                            }).unwrap();
+                            fingers.insert(sl.id, position);
+                        }
+                    }
+                    sl.reported_id = sl.id;
+                }
+            }
+        } else if let Some(button_event) = button_event(&evt) {
+            ty.send(button_event).unwrap();
+        }
+    }
+}
+
+fn button_event(evt: &InputEvent) -> Option<DeviceEvent> {
+    if evt.kind == EV_KEY && evt.code == KEY_POWER {
+        Some(DeviceEvent::Button {
+            time: seconds(evt.time),
+            code: ButtonCode::Power,
+            status: if evt.value == 1 { ButtonStatus::Pressed } else
+                                      { ButtonStatus::Released },
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::mem;
+    use calibration::Transform;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc;
+    use geom::Point;
+    use super::{AbsInfo, InputEvent, RecordingHeader, DeviceEvent, FingerStatus, read_header, write_header,
+                read_recorded_event, write_recorded_event, parse_slot_events, MULTI_TOUCH_CODES_B,
+                ABS_X, ABS_MT_SLOT, ABS_MT_TRACKING_ID, ABS_MT_POSITION_X, ABS_MT_POSITION_Y,
+                EV_ABS, EV_SYN, SYN_REPORT};
+
+    fn input_event(kind: u16, code: u16, value: i32) -> InputEvent {
+        let mut evt: InputEvent = unsafe { mem::zeroed() };
+        evt.time.tv_sec = 1_500_000_000;
+        evt.time.tv_usec = 250_000;
+        evt.kind = kind;
+        evt.code = code;
+        evt.value = value;
+        evt
+    }
+
+    fn event(value: i32) -> InputEvent {
+        input_event(EV_ABS, ABS_X, value)
+    }
+
+    #[test]
+    fn recordings_keep_the_header() {
+        let mut ranges = HashMap::new();
+        ranges.insert(ABS_X, AbsInfo { minimum: 0, maximum: 1023, .. AbsInfo::default() });
+        let header = RecordingHeader {
+            product: "snow".to_owned(),
+            transform: Transform::from_ranges(ranges.get(&ABS_X), None, (600, 800)),
+            ranges,
+        };
+        let mut buf = Vec::new();
+        write_header(&mut buf, &header).unwrap();
+        write_recorded_event(&mut buf, &event(-42)).unwrap();
+        let mut reader = Cursor::new(buf);
+        let read = read_header(&mut reader).unwrap().unwrap();
+        assert_eq!(read.product, "snow");
+        assert_eq!(read.transform, header.transform);
+        assert_eq!(read.ranges[&ABS_X].maximum, 1023);
+        let evt = read_recorded_event(&mut reader).unwrap();
+        assert_eq!((evt.time.tv_sec, evt.time.tv_usec), (1_500_000_000, 250_000));
+        assert_eq!((evt.kind, evt.code, evt.value), (EV_ABS, ABS_X, -42));
+    }
+
+    // The layout doesn't depend on the size of `timeval`.
+    #[test]
+    fn recorded_events_have_a_fixed_size() {
+        let mut buf = Vec::new();
+        write_recorded_event(&mut buf, &event(7)).unwrap();
+        assert_eq!(buf.len(), 20);
+        assert_eq!(&buf[16..], &[7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn kernel_dumps_have_no_header() {
+        let evt = event(42);
+        let bytes = unsafe {
+            ::std::slice::from_raw_parts(&evt as *const InputEvent as *const u8, mem::size_of::<InputEvent>())
+        };
+        let mut reader = Cursor::new(bytes.to_vec());
+        assert!(read_header(&mut reader).unwrap().is_none());
+        assert_eq!(super::read_event(&mut reader).unwrap().value, 42);
+    }
+
+    #[test]
+    fn slots_are_tracked_separately() {
+        let frames: &[&[(u16, i32)]] = &[
+            &[(ABS_MT_SLOT, 0), (ABS_MT_TRACKING_ID, 10), (ABS_MT_POSITION_X, 100), (ABS_MT_POSITION_Y, 200),
+              (ABS_MT_SLOT, 1), (ABS_MT_TRACKING_ID, 11), (ABS_MT_POSITION_X, 300), (ABS_MT_POSITION_Y, 400)],
+            &[(ABS_MT_SLOT, 0), (ABS_MT_POSITION_X, 110)],
+            &[(ABS_MT_TRACKING_ID, -1), (ABS_MT_SLOT, 1), (ABS_MT_POSITION_Y, 420)],
+            // A new contact replaces the one of the current slot.
+            &[(ABS_MT_TRACKING_ID, 12), (ABS_MT_POSITION_X, 50), (ABS_MT_POSITION_Y, 60)],
+        ];
+        let (tx, rx) = mpsc::channel();
+        for frame in frames {
+            for &(code, value) in frame.iter() {
+                tx.send(input_event(EV_ABS, code, value)).unwrap();
+            }
+            tx.send(input_event(EV_SYN, SYN_REPORT, 0)).unwrap();
         }
+        drop(tx);
+        let (ty, ry) = mpsc::channel();
+        parse_slot_events(rx, ty, HashMap::new(), Arc::new(Mutex::new(Transform::identity())), MULTI_TOUCH_CODES_B);
+        let events: Vec<(i32, FingerStatus, Point)> = ry.iter().filter_map(|evt| {
+            match evt {
+                DeviceEvent::Finger { id, status, position, .. } => Some((id, status, position)),
+                _ => None,
+            }
+        }).collect();
+        // The slots of a frame are reported in any order.
+        let contact = |id: i32| -> Vec<(FingerStatus, Point)> {
+            events.iter().filter(|e| e.0 == id).map(|e| (e.1, e.2)).collect()
+        };
+        assert_eq!(events.len(), 7);
+        assert_eq!(contact(10), vec![(FingerStatus::Down, pt!(100, 200)), (FingerStatus::Motion, pt!(110, 200)),
+                                     (FingerStatus::Up, pt!(110, 200))]);
+        assert_eq!(contact(11), vec![(FingerStatus::Down, pt!(300, 400)), (FingerStatus::Motion, pt!(300, 420)),
+                                     (FingerStatus::Up, pt!(300, 420))]);
+        assert_eq!(contact(12), vec![(FingerStatus::Down, pt!(50, 60))]);
     }
 }
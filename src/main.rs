@@ -4,14 +4,40 @@ extern crate png;
 
 #[macro_use]
 mod geom;
+mod unit;
 mod device;
+mod calibration;
 mod input;
+mod gesture;
+mod bindings;
+mod display;
+mod pixmap;
+mod template;
+mod document;
+mod svg;
+mod pdf;
+mod storage;
+mod notebook;
 mod framebuffer;
+mod headless;
+mod canvas;
 mod sketch;
 
+use std::env;
+use std::process;
+use framebuffer::Framebuffer;
 use sketch::Sketch;
 
 fn main() {
-    let mut sketch = Sketch::new();
+    let fb = Framebuffer::new("/dev/fb0").unwrap();
+    let mut sketch = Sketch::new(fb).unwrap_or_else(|e| {
+        eprintln!("Can't start: {}", e);
+        process::exit(1);
+    });
+    if let Some(path) = env::args().nth(1) {
+        if let Err(e) = sketch.open(&path) {
+            eprintln!("Can't open {}: {}", path, e);
+        }
+    }
     sketch.run();
 }
@@ -0,0 +1,242 @@
+use std::path::{Path, PathBuf};
+use std::io;
+use std::fs;
+use document::Document;
+use template::Template;
+use storage::{save_document, load_document};
+
+// An ordered list of pages.
+// If the notebook has a directory, each page is stored there as `page-NNN.sketch`.
+pub struct Notebook {
+    pub pages: Vec<Document>,
+    pub current: usize,
+    pub path: Option<PathBuf>,
+    // Whether each page is the same as its file.
+    saved: Vec<bool>,
+    dims: (u32, u32),
+    template: Option<Template>,
+}
+
+impl Notebook {
+    pub fn new(dims: (u32, u32), template: Option<Template>) -> Notebook {
+        let mut notebook = Notebook {
+            pages: Vec::new(),
+            current: 0,
+            path: None,
+            saved: Vec::new(),
+            dims,
+            template,
+        };
+        let page = notebook.blank_page();
+        notebook.pages.push(page);
+        notebook.saved.push(false);
+        notebook
+    }
+
+    // Load the pages stored in the directory at `path`, the directory is created if needed.
+    pub fn load<P: AsRef<Path>>(path: P, dims: (u32, u32), template: Option<Template>) -> io::Result<Notebook> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        let mut notebook = Notebook::new(dims, template);
+        notebook.path = Some(path.to_path_buf());
+        let mut page_paths = page_paths(path)?;
+        page_paths.sort();
+        if !page_paths.is_empty() {
+            notebook.pages.clear();
+            notebook.saved.clear();
+        }
+        for page_path in page_paths {
+            let (mut page, _) = load_document(page_path)?;
+            page.dims = dims;
+            page.background = page.background.map(|bg| bg.fit(dims));
+            page.template = template;
+            notebook.pages.push(page);
+            notebook.saved.push(true);
+        }
+        Ok(notebook)
+    }
+
+    // Write the pages that changed in the notebook's directory, and remove the files of the deleted pages.
+    pub fn save(&mut self, model: &str) -> io::Result<()> {
+        if let Some(ref path) = self.path {
+            let mut saved_paths = Vec::new();
+            for (index, page) in self.pages.iter().enumerate() {
+                let page_path = path.join(format!("page-{:03}.sketch", index + 1));
+                if !self.saved[index] {
+                    save_document(&page_path, page, model)?;
+                    self.saved[index] = true;
+                }
+                saved_paths.push(page_path);
+            }
+            for page_path in page_paths(path)? {
+                if !saved_paths.contains(&page_path) {
+                    fs::remove_file(page_path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn blank_page(&self) -> Document {
+        let mut page = Document::new(self.dims);
+        page.template = self.template;
+        page
+    }
+
+    pub fn page(&self) -> &Document {
+        &self.pages[self.current]
+    }
+
+    // The current page is assumed to be modified.
+    pub fn page_mut(&mut self) -> &mut Document {
+        self.saved[self.current] = false;
+        &mut self.pages[self.current]
+    }
+
+    // The pages from `index` onward are stored in different files.
+    fn shift(&mut self, index: usize) {
+        for saved in &mut self.saved[index..] {
+            *saved = false;
+        }
+    }
+
+    pub fn set_template(&mut self, template: Option<Template>) {
+        self.template = template;
+        for page in &mut self.pages {
+            page.template = template;
+        }
+    }
+
+    pub fn template(&self) -> Option<Template> {
+        self.template
+    }
+
+    // Whether every page is empty.
+    pub fn is_empty(&self) -> bool {
+        self.pages.iter().all(|page| page.is_empty())
+    }
+
+    // Replace all the pages by a blank page.
+    pub fn clear(&mut self) {
+        let page = self.blank_page();
+        self.pages = vec![page];
+        self.saved = vec![false];
+        self.current = 0;
+    }
+
+    // Go to the next page. A new page is added after the last page, unless it's empty.
+    // Returns whether the current page changed.
+    pub fn next(&mut self) -> bool {
+        if self.current + 1 < self.pages.len() {
+            self.current += 1;
+            true
+        } else if !self.page().is_empty() {
+            self.insert();
+            true
+        } else {
+            false
+        }
+    }
+
+    // Returns whether the current page changed.
+    pub fn previous(&mut self) -> bool {
+        if self.current > 0 {
+            self.current -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Insert a blank page after the current page and go to it.
+    pub fn insert(&mut self) {
+        let page = self.blank_page();
+        self.current += 1;
+        self.pages.insert(self.current, page);
+        self.saved.insert(self.current, false);
+        let current = self.current;
+        self.shift(current);
+    }
+
+    // Remove the current page, the last remaining page is replaced by a blank page.
+    pub fn delete(&mut self) {
+        self.pages.remove(self.current);
+        self.saved.remove(self.current);
+        if self.pages.is_empty() {
+            let page = self.blank_page();
+            self.pages.push(page);
+            self.saved.push(false);
+        }
+        if self.current < self.pages.len() {
+            let current = self.current;
+            self.shift(current);
+        } else {
+            self.current = self.pages.len() - 1;
+        }
+    }
+}
+
+fn page_paths(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        let is_page = entry_path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+            name.starts_with("page-") && name.ends_with(".sketch")
+        });
+        if is_page {
+            paths.push(entry_path);
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use document::{Stroke, Tool, PressureCurve};
+    use super::{Notebook, page_paths};
+
+    // Mark the current page with a stroke that starts at `x`.
+    fn mark(notebook: &mut Notebook, x: i32) {
+        let mut stroke = Stroke::new(Tool::Pen, 2.0, 0x00, PressureCurve::default(), false);
+        stroke.push(pt!(x, 10), 0.0, 1.0);
+        notebook.page_mut().push(stroke);
+    }
+
+    fn marks(notebook: &Notebook) -> Vec<i32> {
+        notebook.pages.iter().map(|page| page.strokes.first().map_or(0, |s| s.points[0].position.x)).collect()
+    }
+
+    #[test]
+    fn pages_are_saved_in_order() {
+        let path = env::temp_dir().join(format!("sketch-notebook-{}", process::id()));
+        let mut notebook = Notebook::load(&path, (300, 400), None).unwrap();
+        mark(&mut notebook, 1);
+        assert!(notebook.next());
+        mark(&mut notebook, 2);
+        assert!(notebook.previous());
+        notebook.insert();
+        mark(&mut notebook, 3);
+        assert_eq!(notebook.current, 1);
+        assert_eq!(marks(&notebook), vec![1, 3, 2]);
+        notebook.save("Nova").unwrap();
+        assert_eq!(page_paths(&path).unwrap().len(), 3);
+        assert_eq!(marks(&Notebook::load(&path, (300, 400), None).unwrap()), vec![1, 3, 2]);
+
+        notebook.delete();
+        assert_eq!(notebook.current, 1);
+        notebook.save("Nova").unwrap();
+        assert_eq!(page_paths(&path).unwrap().len(), 2);
+        assert_eq!(marks(&Notebook::load(&path, (300, 400), None).unwrap()), vec![1, 2]);
+
+        // Deleting the last page goes to the previous one.
+        notebook.delete();
+        assert_eq!(notebook.current, 0);
+        notebook.delete();
+        assert_eq!(marks(&notebook), vec![0]);
+        notebook.save("Nova").unwrap();
+        let result = Notebook::load(&path, (300, 400), None);
+        fs::remove_dir_all(&path).unwrap();
+        assert_eq!(marks(&result.unwrap()), vec![0]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::path::Path;
+use std::io::{self, Write};
+use std::fs::File;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use document::{Document, Stroke, Layer, LAYERS};
+use pixmap::Pixmap;
+use template::{TEMPLATE_COLOR, DOT_RADIUS};
+
+// Write the given layers of each document as a page of a PDF file.
+// The size of the pages is derived from the dimensions of the documents and the given DPI.
+pub fn save_pdf(path: &Path, documents: &[&Document], dpi: u16, layers: &[Layer]) -> io::Result<()> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut offsets = Vec::new();
+    let count = documents.len();
+    // Objects: catalog, page tree, one page and one content stream per document,
+    // then the backgrounds, the erasers and the masks of the pages.
+    writeln!(buf, "%PDF-1.4")?;
+    offsets.push(buf.len());
+    writeln!(buf, "1 0 obj\n<< /Type /Catalog /Pages 2 0 R >>\nendobj")?;
+    offsets.push(buf.len());
+    let kids: Vec<String> = (0..count).map(|i| format!("{} 0 R", 3 + 2 * i)).collect();
+    writeln!(buf, "2 0 obj\n<< /Type /Pages /Kids [{}] /Count {} >>\nendobj", kids.join(" "), count)?;
+    let scale = 72.0 / dpi as f32;
+    // The objects stored after the pages.
+    let mut objects: Vec<Vec<u8>> = Vec::new();
+    let first = 3 + 2 * count;
+    for (i, document) in documents.iter().enumerate() {
+        let (width, height) = (document.dims.0 as f32 * scale, document.dims.1 as f32 * scale);
+        let background = document.background.as_ref().filter(|_| layers.contains(&Layer::Background));
+        let mut masks = Vec::new();
+        let content = page_content(document, scale, height, layers, background.is_some(), &mut masks);
+        let mut xobjects = String::new();
+        if let Some(background) = background {
+            objects.push(image_object(background)?);
+            xobjects += &format!(" /Bg {} 0 R", first + objects.len() - 1);
+        }
+        // Each eraser is a form shared by the masks it belongs to.
+        let mut erasers = HashMap::new();
+        for &index in masks.iter().flat_map(|erasers: &Vec<usize>| erasers.iter()) {
+            if let Entry::Vacant(entry) = erasers.entry(index) {
+                let mut data = Vec::new();
+                write_stroke(&mut data, &document.strokes[index], 0x00);
+                objects.push(stream_object(&format!("/Type /XObject /Subtype /Form /BBox [0 0 {} {}]",
+                                                    document.dims.0, document.dims.1), &data));
+                entry.insert(first + objects.len() - 1);
+            }
+        }
+        // The luminosity of a mask is white, except where its erasers are drawn in black.
+        let mut states = String::new();
+        for (k, mask) in masks.iter().enumerate() {
+            let mut data = Vec::new();
+            writeln!(data, "1 g 0 0 {} {} re f 1 J 1 j", document.dims.0, document.dims.1)?;
+            let mut names = String::new();
+            for index in mask {
+                writeln!(data, "/E{} Do", index)?;
+                names += &format!(" /E{} {} 0 R", index, erasers[index]);
+            }
+            objects.push(stream_object(&format!("/Type /XObject /Subtype /Form /BBox [0 0 {} {}] \
+                                                 /Group << /S /Transparency /CS /DeviceGray >> \
+                                                 /Resources << /XObject <<{} >> >>",
+                                                document.dims.0, document.dims.1, names), &data));
+            objects.push(format!("<< /Type /ExtGState /SMask << /S /Luminosity /G {} 0 R >> >>",
+                                 first + objects.len() - 1).into_bytes());
+            states += &format!(" /M{} {} 0 R", k, first + objects.len() - 1);
+        }
+        let mut resources = String::new();
+        if !xobjects.is_empty() {
+            resources += &format!(" /XObject <<{} >>", xobjects);
+        }
+        if !states.is_empty() {
+            resources += &format!(" /ExtGState <<{} >>", states);
+        }
+        if !resources.is_empty() {
+            resources = format!(" /Resources <<{} >>", resources);
+        }
+        offsets.push(buf.len());
+        writeln!(buf, "{} 0 obj\n<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Contents {} 0 R{} >>\nendobj",
+                 3 + 2 * i, width, height, 4 + 2 * i, resources)?;
+        offsets.push(buf.len());
+        writeln!(buf, "{} 0 obj\n<< /Length {} >>\nstream", 4 + 2 * i, content.len())?;
+        buf.extend_from_slice(&content);
+        writeln!(buf, "\nendstream\nendobj")?;
+    }
+    for (k, object) in objects.iter().enumerate() {
+        offsets.push(buf.len());
+        writeln!(buf, "{} 0 obj", first + k)?;
+        buf.extend_from_slice(object);
+        writeln!(buf, "\nendobj")?;
+    }
+    let xref_offset = buf.len();
+    writeln!(buf, "xref\n0 {}\n0000000000 65535 f ", offsets.len() + 1)?;
+    for offset in &offsets {
+        writeln!(buf, "{:010} 00000 n ", offset)?;
+    }
+    writeln!(buf, "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF", offsets.len() + 1, xref_offset)?;
+    File::create(path)?.write_all(&buf)
+}
+
+fn stream_object(dictionary: &str, data: &[u8]) -> Vec<u8> {
+    let mut object = format!("<< {} /Length {} >>\nstream\n", dictionary, data.len()).into_bytes();
+    object.extend_from_slice(data);
+    object.extend_from_slice(b"\nendstream");
+    object
+}
+
+// The compressed data of a PNG file can be decoded by a PDF reader, given the PNG predictors.
+fn image_object(image: &Pixmap) -> io::Result<Vec<u8>> {
+    let data = png_data(&image.to_png()?);
+    Ok(stream_object(&format!("/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceGray \
+                               /BitsPerComponent 8 /Filter /FlateDecode \
+                               /DecodeParms << /Predictor 15 /Colors 1 /BitsPerComponent 8 /Columns {} >>",
+                              image.width, image.height, image.width), &data))
+}
+
+// The strokes are drawn in pixels: the y axis is flipped and the pixels are scaled to points.
+// The background, if any, is the image named `Bg`. The strokes drawn before erasers are painted
+// through the masks named `M0`, `M1`, etc., whose erasers are pushed to `masks`.
+fn page_content(document: &Document, scale: f32, height: f32, layers: &[Layer], background: bool,
+                masks: &mut Vec<Vec<usize>>) -> Vec<u8> {
+    let mut content = Vec::new();
+    writeln!(content, "{} 0 0 {} 0 {} cm 1 J 1 j", scale, -scale, height).unwrap();
+    if background {
+        let (width, height) = document.dims;
+        writeln!(content, "q {} 0 0 {} 0 {} cm /Bg Do Q", width, -(height as i32), height).unwrap();
+    }
+    for layer in LAYERS.iter().filter(|l| layers.contains(l)) {
+        if let (&Layer::Background, Some(ref template)) = (layer, document.template) {
+            let masked = begin_mask(&mut content, document.template_erasers(), masks);
+            writeln!(content, "{} G 1 w", TEMPLATE_COLOR as f32 / 255.0).unwrap();
+            for (a, b) in template.lines(document.dims) {
+                writeln!(content, "{} {} m {} {} l S", a.0, a.1, b.0, b.1).unwrap();
+            }
+            writeln!(content, "{} w", 2.0 * DOT_RADIUS).unwrap();
+            for p in template.dots(document.dims) {
+                writeln!(content, "{} {} m {} {} l S", p.0, p.1, p.0, p.1).unwrap();
+            }
+            if masked {
+                writeln!(content, "Q").unwrap();
+            }
+        }
+        for (pens, erasers) in document.erased_runs(*layer) {
+            let masked = begin_mask(&mut content, erasers, masks);
+            for stroke in pens {
+                write_stroke(&mut content, stroke, stroke.color);
+            }
+            if masked {
+                writeln!(content, "Q").unwrap();
+            }
+        }
+    }
+    content
+}
+
+fn write_stroke(content: &mut Vec<u8>, stroke: &Stroke, gray: u8) {
+    writeln!(content, "{} G", gray as f32 / 255.0).unwrap();
+    for (radius, points) in stroke.polylines() {
+        writeln!(content, "{} w", 2.0 * radius).unwrap();
+        for (i, p) in points.iter().enumerate() {
+            writeln!(content, "{:.2} {:.2} {}", p.0, p.1, if i == 0 { "m" } else { "l" }).unwrap();
+        }
+        writeln!(content, "S").unwrap();
+    }
+}
+
+// Save the graphics state and set a mask made of the given erasers, if there are any.
+// Returns whether the state was saved.
+fn begin_mask(content: &mut Vec<u8>, erasers: Vec<usize>, masks: &mut Vec<Vec<usize>>) -> bool {
+    if erasers.is_empty() {
+        return false;
+    }
+    writeln!(content, "q /M{} gs", masks.len()).unwrap();
+    masks.push(erasers);
+    true
+}
+
+// The concatenated data of the IDAT chunks of a PNG file.
+fn png_data(png: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    // Skip the signature.
+    let mut offset = 8;
+    while offset + 8 <= png.len() {
+        let length = u32::from_be_bytes([png[offset], png[offset + 1], png[offset + 2], png[offset + 3]]) as usize;
+        let start = offset + 8;
+        let end = cmp::min(start + length, png.len());
+        if &png[offset + 4..offset + 8] == b"IDAT" {
+            data.extend_from_slice(&png[start..end]);
+        }
+        // Skip the CRC.
+        offset = end + 4;
+    }
+    data
+}
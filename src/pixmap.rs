@@ -0,0 +1,112 @@
+extern crate png;
+
+use std::path::Path;
+use std::io;
+use std::fs::File;
+use std::cmp;
+use png::HasParameters;
+use storage::MAX_SIDE;
+
+// A grayscale image.
+#[derive(Debug, Clone)]
+pub struct Pixmap {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Pixmap {
+    pub fn new(width: u32, height: u32) -> Pixmap {
+        Pixmap {
+            width,
+            height,
+            data: vec![0xff; (width * height) as usize],
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> u8 {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, gray: u8) {
+        self.data[(y * self.width + x) as usize] = gray;
+    }
+
+    // Decode a PNG file. The colors are converted to gray levels
+    // and the transparent pixels are blended with a white background.
+    pub fn from_png<P: AsRef<Path>>(path: P) -> io::Result<Pixmap> {
+        let decoder = png::Decoder::new(File::open(path)?);
+        // The palettes and the low bit depths are expanded, the high bit depths are stripped.
+        let (info, mut reader) = decoder.read_info()?;
+        if info.width > MAX_SIDE || info.height > MAX_SIDE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "image too large"));
+        }
+        let mut buf = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buf)?;
+        let channels = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            png::ColorType::Indexed => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpanded palette"));
+            },
+        };
+        let mut pixmap = Pixmap::new(info.width, info.height);
+        for y in 0..info.height {
+            let row = &buf[y as usize * info.line_size..];
+            for x in 0..info.width {
+                let px = &row[x as usize * channels..(x as usize + 1) * channels];
+                let (gray, alpha) = match channels {
+                    1 => (px[0] as u32, 255),
+                    2 => (px[0] as u32, px[1] as u32),
+                    // ITU-R BT.601 luma.
+                    3 => ((299 * px[0] as u32 + 587 * px[1] as u32 + 114 * px[2] as u32) / 1000, 255),
+                    _ => ((299 * px[0] as u32 + 587 * px[1] as u32 + 114 * px[2] as u32) / 1000, px[3] as u32),
+                };
+                pixmap.set_pixel(x, y, ((gray * alpha + 255 * (255 - alpha)) / 255) as u8);
+            }
+        }
+        Ok(pixmap)
+    }
+
+    // Encode the pixmap as a grayscale PNG file.
+    pub fn to_png(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut buf, self.width, self.height);
+            encoder.set(png::ColorType::Grayscale).set(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&self.data)?;
+        }
+        Ok(buf)
+    }
+
+    // Center the pixmap on a white pixmap of the given dimensions.
+    // If the pixmap doesn't fit, it's scaled down first, preserving its aspect ratio.
+    pub fn fit(&self, dims: (u32, u32)) -> Pixmap {
+        let scale = (dims.0 as f32 / self.width as f32).min(dims.1 as f32 / self.height as f32).min(1.0);
+        let width = cmp::max(1, (self.width as f32 * scale) as u32);
+        let height = cmp::max(1, (self.height as f32 * scale) as u32);
+        let (dx, dy) = ((dims.0 - width) / 2, (dims.1 - height) / 2);
+        let mut pixmap = Pixmap::new(dims.0, dims.1);
+        for y in 0..height {
+            // Each pixel is the average of the source pixels it covers.
+            let y_min = y * self.height / height;
+            let y_max = cmp::max(y_min + 1, (y + 1) * self.height / height);
+            for x in 0..width {
+                let x_min = x * self.width / width;
+                let x_max = cmp::max(x_min + 1, (x + 1) * self.width / width);
+                let mut sum = 0;
+                for sy in y_min..y_max {
+                    for sx in x_min..x_max {
+                        sum += self.get_pixel(sx, sy) as u32;
+                    }
+                }
+                let count = (y_max - y_min) * (x_max - x_min);
+                pixmap.set_pixel(dx + x, dy + y, (sum / count) as u8);
+            }
+        }
+        pixmap
+    }
+}
@@ -3,114 +3,930 @@ extern crate libc;
 extern crate png;
 
 use std::collections::HashMap;
-use framebuffer::{Framebuffer, Mode};
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::path::Path;
+use std::env;
+use std::io;
+use display::{Display, Clipped, Dithered, Mode};
 use input::{Input, DeviceEvent, FingerStatus, ButtonStatus, ButtonCode};
+use gesture::{gesture_events, GestureEvent};
+use bindings::{bindings, Gesture, Command};
+use calibration::{Transform, calibration_path};
+use pixmap::Pixmap;
+use template::{Template, TemplateKind};
+use headless::HeadlessDisplay;
+use notebook::Notebook;
+use document::{Document, Stroke, Tool, Layer, LAYERS, PressureCurve, absorb};
+use svg::save_svg;
+use pdf::save_pdf;
+use storage::{save_document, load_document};
+use device::Device;
+use unit::mm_to_in;
 use geom::{Point, Rectangle};
 
 const UPDATE_INTERVAL: f64 = 1.0 / 60.0;
 const INVERSE_INTERVAL: f64 = 2.0;
+const CROSSHAIR_RADIUS_MM: f32 = 4.0;
+const PEN_WIDTH_MM: f32 = 0.5;
+const PEN_COLORS: [u8; 3] = [0x00, 0x55, 0xaa];
+const ERASER_WIDTH_MM: f32 = 3.0;
+const TEMPLATE_SPACING_MM: f32 = 5.0;
+const INDICATOR_RADIUS_MM: f32 = 0.8;
+const INDICATOR_SPACING_MM: f32 = 3.0;
+const INDICATOR_MARGIN_MM: f32 = 4.0;
 
-pub struct Sketch {
-    fb: Framebuffer,
-    input: Input,
-    has_drawn: bool,
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Smoothing {
+    Off,
+    // Draw each segment once the next point is known.
+    Delayed,
+    // Draw each segment right away and smooth it once the next point is known.
+    LowLatency,
+}
+
+// The formats in which the drawings are saved.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    Png,
+    Svg,
+    Pdf,
+    // Lossless, can be opened again.
+    Native,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EraserMode {
+    // Paint with the layers below the strokes.
+    Paint,
+    // Remove the strokes touched by the eraser.
+    Strokes,
+}
+
+pub struct Sketch<D: Display> {
+    fb: D,
+    events: Receiver<GestureEvent>,
+    transform: Arc<Mutex<Transform>>,
+    bindings: HashMap<Gesture, Command>,
+    fingers: HashMap<i32, TouchState>,
+    notebook: Notebook,
+    // Width of the pen, in pixels.
+    pen_width: f32,
+    pen_curve: PressureCurve,
+    pen_colors: Vec<u8>,
+    // Index of the current color in `pen_colors`.
+    pen_color: usize,
+    eraser_mode: EraserMode,
+    // Width of the eraser, in pixels.
+    eraser_width: f32,
+    // Whether the fingers are erasing.
+    erasing: bool,
+    // Number of strokes added since the last time all the fingers were lifted.
+    group_strokes: usize,
+    smoothing: Smoothing,
+    // Whether the finished strokes are drawn with anti-aliased edges.
+    antialias: bool,
+    formats: Vec<Format>,
+    // Whether the template is part of the saved drawings.
+    export_template: bool,
+    // The layer that receives the new strokes.
+    layer: Layer,
+    visible_layers: Vec<Layer>,
+    // The layers that are part of the saved drawings, the visible layers if `None`.
+    export_layers: Option<Vec<Layer>>,
+    // When the power button was last pressed.
+    last_pressed_time: f64,
 }
 
 struct TouchState {
-    pt: Point,
+    stroke: Stroke,
     rect: Rectangle,
     last_update_time: f64,
 }
 
 impl TouchState {
-    fn new(pt: Point, rect: Rectangle) -> TouchState {
+    fn new(stroke: Stroke, rect: Rectangle) -> TouchState {
         TouchState {
-            pt: pt,
+            stroke,
             rect: rect,
             last_update_time: 0.0,
         }
     }
 }
 
-impl Sketch {
-    pub fn new() -> Sketch {
-        let fb = Framebuffer::new("/dev/fb0").unwrap();
+impl<D: Display> Sketch<D> {
+    pub fn new(fb: D) -> io::Result<Sketch<D>> {
         let input = Input::new(vec!["/dev/input/event0".to_owned(),
                                     "/dev/input/event1".to_owned()],
-                               fb.dims());
-        Sketch {
-            fb: fb,
-            input: input,
-            has_drawn: false,
+                               fb.dims())?;
+        let events = gesture_events(input.events);
+        Sketch::with_events(fb, events, input.transform)
+    }
+
+    // A sketch that reads its events from the given channel.
+    fn with_events(fb: D, events: Receiver<GestureEvent>, transform: Arc<Mutex<Transform>>) -> io::Result<Sketch<D>> {
+        let pen_width_mm = env::var("SKETCH_PEN_WIDTH").ok()
+                                                      .and_then(|v| v.parse().ok())
+                                                      .unwrap_or(PEN_WIDTH_MM);
+        let dpi = Device::current().dpi as f32;
+        let pen_width = mm_to_in(pen_width_mm) * dpi;
+        let eraser_width_mm = env::var("SKETCH_ERASER_WIDTH").ok()
+                                                            .and_then(|v| v.parse().ok())
+                                                            .unwrap_or(ERASER_WIDTH_MM);
+        let eraser_width = mm_to_in(eraser_width_mm) * dpi;
+        let smoothing = match env::var("SKETCH_SMOOTHING").as_ref().map(String::as_str) {
+            Ok("delayed") => Smoothing::Delayed,
+            Ok("low-latency") => Smoothing::LowLatency,
+            _ => Smoothing::Off,
+        };
+        let antialias = env::var("SKETCH_ANTIALIASING").ok().is_none_or(|v| v != "0");
+        let formats = env::var("SKETCH_EXPORT").ok().map(|v| {
+            v.split(',').filter_map(|name| {
+                match name.trim() {
+                    "png" => Some(Format::Png),
+                    "svg" => Some(Format::Svg),
+                    "pdf" => Some(Format::Pdf),
+                    "sketch" => Some(Format::Native),
+                    _ => None,
+                }
+            }).collect::<Vec<Format>>()
+        }).filter(|formats| !formats.is_empty()).unwrap_or_else(|| vec![Format::Png, Format::Native]);
+        let eraser_mode = if env::var("SKETCH_ERASER").ok().is_some_and(|v| v == "paint") {
+            EraserMode::Paint
+        } else {
+            EraserMode::Strokes
+        };
+        let template_spacing_mm = env::var("SKETCH_TEMPLATE_SPACING").ok()
+                                                                     .and_then(|v| v.parse().ok())
+                                                                     .filter(|&spacing: &f32| spacing > 0.0)
+                                                                     .unwrap_or(TEMPLATE_SPACING_MM);
+        let template = env::var("SKETCH_TEMPLATE").ok().and_then(|v| TemplateKind::parse(&v)).map(|kind| {
+            Template {
+                kind,
+                spacing: mm_to_in(template_spacing_mm) * dpi,
+                erasable: env::var("SKETCH_ERASE_TEMPLATE").ok().is_some_and(|v| v == "1"),
+            }
+        });
+        let notebook = if let Ok(path) = env::var("SKETCH_NOTEBOOK") {
+            Notebook::load(path, fb.dims(), template)?
+        } else {
+            Notebook::new(fb.dims(), template)
+        };
+        let export_template = env::var("SKETCH_EXPORT_TEMPLATE").ok().is_none_or(|v| v != "0");
+        let export_layers = env::var("SKETCH_EXPORT_LAYERS").ok().map(|v| {
+            v.split(',').filter_map(|name| Layer::parse(name.trim())).collect::<Vec<Layer>>()
+        });
+        let pen_colors = env::var("SKETCH_PEN_COLORS").ok().map(|v| {
+            v.split_whitespace().filter_map(|c| u8::from_str_radix(c, 16).ok()).collect::<Vec<u8>>()
+        }).filter(|colors| !colors.is_empty()).unwrap_or_else(|| PEN_COLORS.to_vec());
+        let mut pen_curve = PressureCurve::default();
+        if let Some(min) = env::var("SKETCH_PRESSURE_MIN").ok().and_then(|v| v.parse().ok()) {
+            pen_curve.min = min;
+        }
+        if let Some(gamma) = env::var("SKETCH_PRESSURE_GAMMA").ok().and_then(|v| v.parse().ok()) {
+            pen_curve.gamma = gamma;
         }
+        Ok(Sketch {
+            fb: fb,
+            events,
+            transform,
+            bindings: bindings(),
+            fingers: HashMap::new(),
+            notebook,
+            pen_width,
+            pen_curve,
+            pen_colors,
+            pen_color: 0,
+            eraser_mode,
+            eraser_width,
+            erasing: false,
+            group_strokes: 0,
+            smoothing,
+            antialias,
+            formats,
+            export_template,
+            layer: Layer::Ink,
+            visible_layers: LAYERS.to_vec(),
+            export_layers,
+            last_pressed_time: 0.0,
+        })
     }
+
     pub fn run(&mut self) {
-        let mut fingers: HashMap<i32, TouchState> = HashMap::new();
-        let mut last_pressed_time = 0.0;
-        self.clear();
-        while let Ok(evt) = self.input.events.recv() {
-            match evt {
-                DeviceEvent::Finger { status: FingerStatus::Motion, id, position, time } => {
-                    if let Some(ts) = fingers.get_mut(&id) {
-                        ts.rect.merge(&position);
-                        self.fb.draw_line_segment(&position, &ts.pt, 0x00);
-                        if (time - ts.last_update_time).abs() > UPDATE_INTERVAL {
-                            self.fb.update(ts.rect, Mode::Fast).unwrap();
-                            ts.last_update_time = time;
-                            ts.rect = Rectangle::from_point(&position);
+        // The taps of a replayed recording aren't meant for the calibration:
+        // its transform comes with the recording.
+        if !calibration_path().exists() && env::var_os("SKETCH_REPLAY").is_none() {
+            self.calibrate();
+        }
+        self.redraw();
+        while let Ok(evt) = self.events.recv() {
+            if !self.handle(evt) {
+                break;
+            }
+        }
+    }
+
+    // Returns `false` when the application should quit.
+    fn handle(&mut self, evt: GestureEvent) -> bool {
+        match evt {
+            GestureEvent::Relay(DeviceEvent::Finger { status: FingerStatus::Down, id, position, time, pressure }) => {
+                self.finger_down(id, position, time, pressure);
+            },
+            GestureEvent::Relay(DeviceEvent::Finger { status: FingerStatus::Motion, id, position, time, pressure }) => {
+                self.finger_motion(id, position, time, pressure);
+            },
+            GestureEvent::Relay(DeviceEvent::Finger { status: FingerStatus::Up, id, position, time, .. }) => {
+                self.finger_up(id, position, time);
+            },
+            GestureEvent::Relay(DeviceEvent::Button { status, code: ButtonCode::Power, time }) => {
+                match status {
+                    ButtonStatus::Pressed => self.last_pressed_time = time,
+                    ButtonStatus::Released => {
+                        if (time - self.last_pressed_time).abs() < INVERSE_INTERVAL {
+                            if self.notebook.path.is_some() {
+                                self.save();
+                                // Keep running if the pages couldn't be saved.
+                                return !self.save_notebook();
+                            } else if !self.notebook.is_empty() {
+                                self.save();
+                                self.notebook.clear();
+                                self.group_strokes = 0;
+                                self.redraw();
+                            } else {
+                                return false;
+                            }
+                        } else {
+                            self.invert();
                         }
-                        ts.pt = position;
                     }
+                }
+            },
+            _ => {
+                if let Some(gesture) = Gesture::from_event(&evt) {
+                    if let Some(&command) = self.bindings.get(&gesture) {
+                        self.dispatch(gesture, command);
+                    }
+                }
+            },
+        }
+        true
+    }
+
+    fn finger_down(&mut self, id: i32, position: Point, time: f64, pressure: f32) {
+        if self.fingers.is_empty() {
+            self.group_strokes = 0;
+            self.notebook.page_mut().begin_group();
+        }
+        let smooth = self.smoothing != Smoothing::Off;
+        let mut stroke = if self.erasing {
+            Stroke::new(Tool::Eraser, self.eraser_width, 0xff, PressureCurve { min: 1.0, gamma: 1.0 }, smooth)
+        } else {
+            Stroke::new(Tool::Pen, self.pen_width, self.pen_colors[self.pen_color], self.pen_curve, smooth)
+        };
+        stroke.layer = self.layer;
+        stroke.push(position, time, pressure);
+        self.fingers.insert(id, TouchState::new(stroke, Rectangle::from_point(&position)));
+    }
+
+    fn finger_motion(&mut self, id: i32, position: Point, time: f64, pressure: f32) {
+        let mut replaced = None;
+        if let Some(ts) = self.fingers.get_mut(&id) {
+            ts.stroke.push(position, time, pressure);
+            let index = ts.stroke.points.len() - 1;
+            if ts.stroke.tool == Tool::Eraser && self.eraser_mode == EraserMode::Strokes {
+                let start = ts.stroke.points[index - 1].position;
+                let merge = index > 1;
+                replaced = self.notebook.page_mut().erase(&start, &position, ts.stroke.width / 2.0, merge, self.layer);
+            } else {
+                let mut fb = Dithered::new(&mut self.fb);
+                match self.smoothing {
+                    Smoothing::Off => {
+                        let rect = ts.stroke.render_segment(&mut fb, index);
+                        ts.rect.absorb(&rect);
+                    },
+                    // The previous segment can now be smoothed.
+                    Smoothing::Delayed => {
+                        if index > 1 {
+                            let rect = ts.stroke.render_segment(&mut fb, index - 1);
+                            ts.rect.absorb(&rect);
+                        }
+                    },
+                    // Draw a straight segment right away and smooth it once the next point is known.
+                    Smoothing::LowLatency => {
+                        let rect = ts.stroke.render_straight_segment(&mut fb, index);
+                        ts.rect.absorb(&rect);
+                        if index > 1 {
+                            let mut rect = ts.stroke.segment_bounds(index - 1, false);
+                            rect.absorb(&ts.stroke.segment_bounds(index - 1, true));
+                            replaced = Some(rect);
+                        }
+                    },
+                }
+            }
+        }
+        if let Some(rect) = replaced {
+            let erasing_strokes = self.fingers.get(&id).is_some_and(|ts| {
+                ts.stroke.tool == Tool::Eraser && self.eraser_mode == EraserMode::Strokes
+            });
+            if let Some(rect) = self.restore(&rect, !erasing_strokes) {
+                if erasing_strokes {
+                    self.fb.update(rect, Mode::Partial).unwrap();
+                } else if let Some(ts) = self.fingers.get_mut(&id) {
+                    ts.rect.absorb(&rect);
+                }
+            }
+        }
+        if let Some(ts) = self.fingers.get_mut(&id) {
+            if (time - ts.last_update_time).abs() > UPDATE_INTERVAL {
+                self.fb.update(ts.rect, Mode::Fast).unwrap();
+                ts.last_update_time = time;
+                ts.rect = Rectangle::from_point(&position);
+            }
+        }
+    }
+
+    fn finger_up(&mut self, id: i32, position: Point, time: f64) {
+        if let Some(mut ts) = self.fingers.remove(&id) {
+            if ts.stroke.tool == Tool::Eraser && self.eraser_mode == EraserMode::Strokes {
+                return;
+            }
+            // The pressure is null when the finger is lifted.
+            let pressure = ts.stroke.points.last().map_or(1.0, |sp| sp.pressure);
+            ts.stroke.push(position, time, pressure);
+            let index = ts.stroke.points.len() - 1;
+            let mut fb = Dithered::new(&mut self.fb);
+            match self.smoothing {
+                Smoothing::Off => {
+                    let rect = ts.stroke.render_segment(&mut fb, index);
+                    ts.rect.absorb(&rect);
                 },
-                DeviceEvent::Finger { status: FingerStatus::Down, id, position, .. } => {
-                    fingers.insert(id, TouchState::new(position, Rectangle::from_point(&position)));
+                Smoothing::Delayed => {
+                    for index in index.saturating_sub(1)..index + 1 {
+                        let rect = ts.stroke.render_segment(&mut fb, index);
+                        ts.rect.absorb(&rect);
+                    }
                 },
-                DeviceEvent::Finger { status: FingerStatus::Up, id, position, .. } => {
-                    if let Some(ts) = fingers.get_mut(&id) {
-                        self.fb.draw_line_segment(&position, &ts.pt, 0x00);
-                        self.fb.update(ts.rect, Mode::Fast).unwrap();
+                Smoothing::LowLatency => {
+                    for index in index.saturating_sub(1)..index + 1 {
+                        ts.rect.absorb(&ts.stroke.segment_bounds(index, false));
+                        ts.rect.absorb(&ts.stroke.segment_bounds(index, true));
                     }
-                    fingers.remove(&id);
-                    self.has_drawn = true;
                 },
-                DeviceEvent::Button { status, code: ButtonCode::Power, time } => {
-                    match status {
-                        ButtonStatus::Pressed => last_pressed_time = time,
-                        ButtonStatus::Released => {
-                            if (time - last_pressed_time).abs() < INVERSE_INTERVAL {
-                                if self.has_drawn {
-                                    self.save();
-                                    self.clear();
-                                } else {
-                                    break;
-                                }
-                            } else {
-                                self.fb.toggle_inverse();
-                                let (width, height) = self.fb.dims();
-                                if let Ok(token) = self.fb.update(rect!(0, 0, width as i32, height as i32), Mode::Full) {
-                                    self.fb.wait(token).unwrap();
-                                }
-                            }
+            }
+            let rect = ts.rect;
+            let tool = ts.stroke.tool;
+            let color = ts.stroke.color;
+            let bbox = ts.stroke.bounding_box().unwrap_or(rect);
+            self.notebook.page_mut().push(ts.stroke);
+            if tool == Tool::Eraser {
+                // Clear the ghosting left by the fast updates.
+                self.refresh(&bbox);
+            } else if self.antialias || (color != 0x00 && color != 0xff) {
+                // Replace the black and white preview of the stroke by its final version.
+                let mut rect = rect;
+                rect.absorb(&bbox);
+                self.refresh(&rect);
+            } else {
+                if self.smoothing == Smoothing::LowLatency {
+                    self.restore(&rect, true);
+                }
+                self.fb.update(rect, Mode::Fast).unwrap();
+            }
+            self.group_strokes += 1;
+        }
+    }
+
+    // Render the given region of the document and the strokes in progress.
+    // Returns the part of the region that lies within the document.
+    // A preview is meant for the `Fast` mode: it only contains black and white pixels.
+    // The strokes in progress are always previewed.
+    fn restore(&mut self, rect: &Rectangle, preview: bool) -> Option<Rectangle> {
+        let rect = if preview {
+            self.notebook.page().render_rect(&mut Dithered::new(&mut self.fb), rect, &self.visible_layers, false)
+        } else {
+            self.notebook.page().render_rect(&mut self.fb, rect, &self.visible_layers, self.antialias)
+        };
+        if let Some(rect) = rect {
+            self.draw_page_indicator(&rect);
+            let mut fb = Dithered::new(&mut self.fb);
+            for ts in self.fingers.values() {
+                let stroke = &ts.stroke;
+                if stroke.tool == Tool::Eraser && self.eraser_mode == EraserMode::Strokes {
+                    continue;
+                }
+                let last = stroke.points.len() - 1;
+                match self.smoothing {
+                    Smoothing::Off => {
+                        stroke.render_rect(&mut fb, &rect, 0..last + 1, false);
+                    },
+                    Smoothing::Delayed => {
+                        stroke.render_rect(&mut fb, &rect, 0..last, true);
+                    },
+                    Smoothing::LowLatency => {
+                        stroke.render_rect(&mut fb, &rect, 0..last, true);
+                        stroke.render_rect(&mut fb, &rect, last..last + 1, false);
+                    },
+                }
+            }
+        }
+        rect
+    }
+
+    // The strokes drawn by the fingers involved in a bound gesture are discarded.
+    pub fn dispatch(&mut self, gesture: Gesture, command: Command) {
+        let retracted = cmp::min(gesture.fingers_count(), self.group_strokes);
+        self.group_strokes -= retracted;
+        let mut dirty = self.notebook.page_mut().retract(retracted);
+        if gesture == Gesture::Hold || gesture == Gesture::LongHold {
+            for (_, ts) in self.fingers.drain() {
+                if let Some(rect) = ts.stroke.bounding_box() {
+                    absorb(&mut dirty, &rect);
+                }
+            }
+        }
+        match command {
+            Command::Undo => {
+                if let Some(rect) = self.notebook.page_mut().undo() {
+                    absorb(&mut dirty, &rect);
+                }
+            },
+            Command::Redo => {
+                if let Some(rect) = self.notebook.page_mut().redo() {
+                    absorb(&mut dirty, &rect);
+                }
+            },
+            _ => (),
+        }
+        if let Some(rect) = dirty {
+            self.refresh(&rect);
+        }
+        match command {
+            Command::Save => self.save(),
+            Command::Clear => self.clear(),
+            Command::Invert => self.invert(),
+            Command::ToggleEraser => self.erasing = !self.erasing,
+            Command::CyclePenColor => self.pen_color = (self.pen_color + 1) % self.pen_colors.len(),
+            Command::SwitchLayer => {
+                self.layer = if self.layer == Layer::Ink { Layer::Annotation } else { Layer::Ink };
+                // Drawing on a hidden layer would be confusing.
+                if !self.visible_layers.contains(&self.layer) {
+                    self.visible_layers.push(self.layer);
+                    self.redraw();
+                }
+            },
+            Command::ToggleLayer(layer) => {
+                if self.visible_layers.contains(&layer) {
+                    self.visible_layers.retain(|l| *l != layer);
+                    // Keep drawing on a visible layer.
+                    if layer == self.layer {
+                        self.layer = if layer == Layer::Ink { Layer::Annotation } else { Layer::Ink };
+                        if !self.visible_layers.contains(&self.layer) {
+                            self.visible_layers.push(self.layer);
                         }
                     }
-                },
+                } else {
+                    self.visible_layers.push(layer);
+                }
+                self.redraw();
+            },
+            Command::ClearLayer => {
+                let layer = self.layer;
+                if let Some(rect) = self.notebook.page_mut().clear_layer(layer) {
+                    self.refresh(&rect);
+                }
+            },
+            Command::ClearBackground => {
+                if let Some(rect) = self.notebook.page_mut().clear_background() {
+                    self.refresh(&rect);
+                }
+            },
+            Command::Calibrate => {
+                self.calibrate();
+                self.redraw();
+            },
+            Command::NextPage if self.notebook.next() => {
+                self.turn_page();
+            },
+            Command::PreviousPage if self.notebook.previous() => {
+                self.turn_page();
+            },
+            Command::InsertPage => {
+                self.notebook.insert();
+                self.turn_page();
+            },
+            Command::DeletePage => {
+                self.notebook.delete();
+                self.turn_page();
+            },
+            _ => (),
+        }
+    }
+
+    // Show the current page of the notebook.
+    fn turn_page(&mut self) {
+        self.group_strokes = 0;
+        self.save_notebook();
+        self.redraw();
+    }
+
+    // Returns whether the pages were saved.
+    fn save_notebook(&mut self) -> bool {
+        let model = format!("{:?}", Device::current().model);
+        if let Err(e) = self.notebook.save(&model) {
+            eprintln!("Can't save the notebook: {}", e);
+            false
+        } else {
+            true
+        }
+    }
+
+    // Draw, within `rect`, one dot per page at the bottom of the screen.
+    // The dot of the current page is filled. Nothing is drawn if there's only one page.
+    fn draw_page_indicator(&mut self, rect: &Rectangle) {
+        let count = self.notebook.pages.len();
+        if count < 2 {
+            return;
+        }
+        let dpi = Device::current().dpi as f32;
+        let (width, height) = self.fb.dims();
+        let radius = (mm_to_in(INDICATOR_RADIUS_MM) * dpi).round() as u32;
+        let spacing = (mm_to_in(INDICATOR_SPACING_MM) * dpi).min(0.8 * width as f32 / count as f32);
+        let y = height as i32 - (mm_to_in(INDICATOR_MARGIN_MM) * dpi) as i32;
+        let x_start = width as f32 / 2.0 - spacing * (count - 1) as f32 / 2.0;
+        let mut fb = Clipped::new(&mut self.fb, *rect);
+        for index in 0..count {
+            let center = pt!((x_start + spacing * index as f32) as i32, y);
+            fb.draw_disk(&center, radius, 0x00);
+            if index != self.notebook.current {
+                fb.draw_disk(&center, radius.saturating_sub(1), 0xff);
             }
         }
     }
-    pub fn clear(&mut self) {
+
+    // Render the given region of the document and update the screen accordingly.
+    pub fn refresh(&mut self, rect: &Rectangle) {
+        if let Some(rect) = self.restore(rect, false) {
+            self.fb.update(rect, Mode::Partial).unwrap();
+        }
+    }
+
+    pub fn invert(&mut self) {
+        self.fb.toggle_inverse();
         let (width, height) = self.fb.dims();
-        for x in 0..width {
-            for y in 0..height {
-                self.fb.set_pixel(x, y, 0xff);
+        if let Ok(token) = self.fb.update(rect!(0, 0, width as i32, height as i32), Mode::Full) {
+            self.fb.wait(token).unwrap();
+        }
+    }
+
+    // Ask the user to tap a few crosshairs and derive the touch transform from the taps.
+    // The calibration is aborted by pressing the power button.
+    // The previous transform is kept if the calibration fails, and saved anyway, so it isn't asked for again.
+    pub fn calibrate(&mut self) {
+        let (width, height) = self.fb.dims();
+        let (width, height) = (width as i32, height as i32);
+        let dpi = Device::current().dpi;
+        let radius = (mm_to_in(CROSSHAIR_RADIUS_MM) * dpi as f32) as u32;
+        let targets = [pt!(width / 8, height / 8),
+                       pt!(7 * width / 8, height / 8),
+                       pt!(7 * width / 8, 7 * height / 8),
+                       pt!(width / 8, 7 * height / 8),
+                       pt!(width / 2, height / 2)];
+        let previous = *self.transform.lock().unwrap();
+        *self.transform.lock().unwrap() = Transform::identity();
+        let mut samples = Vec::new();
+        'targets: for target in targets.iter() {
+            self.blank();
+            let rect = self.draw_crosshair(target, radius);
+            self.fb.update(rect, Mode::Partial).unwrap();
+            while let Ok(evt) = self.events.recv() {
+                match evt {
+                    GestureEvent::Relay(DeviceEvent::Finger { status: FingerStatus::Up, position, .. }) => {
+                        samples.push((position, *target));
+                        continue 'targets;
+                    },
+                    GestureEvent::Relay(DeviceEvent::Button { status: ButtonStatus::Released, .. }) => {
+                        break 'targets;
+                    },
+                    _ => (),
+                }
             }
         }
+        let transform = if samples.len() == targets.len() {
+            Transform::fit(&samples)
+        } else {
+            None
+        };
+        let transform = transform.unwrap_or(previous);
+        if let Err(e) = transform.save(calibration_path()) {
+            eprintln!("Can't save the calibration: {}", e);
+        }
+        *self.transform.lock().unwrap() = transform;
+    }
+
+    fn draw_crosshair(&mut self, center: &Point, radius: u32) -> Rectangle {
+        let rect = self.fb.draw_disk(center, radius, 0x00);
+        self.fb.draw_disk(center, radius.saturating_sub(2), 0xff);
+        self.fb.draw_disk(center, 1, 0x00);
+        let r = radius as i32;
+        self.fb.draw_line_segment(&pt!(center.x - 2 * r, center.y), &pt!(center.x + 2 * r, center.y), 0x00);
+        self.fb.draw_line_segment(&pt!(center.x, center.y - 2 * r), &pt!(center.x, center.y + 2 * r), 0x00);
+        let mut bounds = rect!(*center - 2 * r, *center + 2 * r + 1);
+        bounds.absorb(&rect);
+        let (width, height) = self.fb.dims();
+        bounds.intersection(&rect!(0, 0, width as i32, height as i32)).unwrap_or(rect)
+    }
+
+    pub fn clear(&mut self) {
+        self.notebook.page_mut().clear();
+        self.redraw();
+    }
+
+    // Replace the document by the one stored at `path`.
+    // A PNG image becomes the background of an empty document.
+    pub fn open<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let dims = self.fb.dims();
+        let path = path.as_ref();
+        let mut document = if path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+            let mut document = Document::new(dims);
+            document.background = Some(Pixmap::from_png(path)?.fit(dims));
+            document
+        } else {
+            let (mut document, _) = load_document(path)?;
+            // The strokes are kept where they were drawn, even if the screen is smaller.
+            document.dims = dims;
+            document.background = document.background.map(|bg| bg.fit(dims));
+            document
+        };
+        document.template = self.notebook.template();
+        *self.notebook.page_mut() = document;
+        self.redraw();
+        Ok(())
+    }
+
+    // Clear the screen without touching the document.
+    fn blank(&mut self) {
+        let (width, height) = self.fb.dims();
+        let rect = rect!(0, 0, width as i32, height as i32);
+        self.fb.fill_rect(&rect, 0xff);
+        if let Ok(token) = self.fb.update(rect, Mode::Full) {
+            self.fb.wait(token).unwrap();
+        }
+    }
+
+    // Render the whole document on a blank screen.
+    pub fn redraw(&mut self) {
+        let (width, height) = self.fb.dims();
+        let rect = rect!(0, 0, width as i32, height as i32);
+        self.notebook.page().render_rect(&mut self.fb, &rect, &self.visible_layers, self.antialias);
+        self.draw_page_indicator(&rect);
         if let Ok(token) = self.fb.update(rect!(0, 0, width as i32, height as i32), Mode::Full) {
             self.fb.wait(token).unwrap();
         }
-        self.has_drawn = false;
     }
 
+    // Export the drawings in the chosen formats, the errors are reported on the standard error.
+    // A PDF file holds all the pages, the other formats get one file per non-empty page.
     pub fn save(&mut self) {
-        self.fb.save(format!("drawing-{}.png", chrono::Local::now().format("%Y%m%d_%H%M%S").to_string()));
+        let stem = format!("drawing-{}", chrono::Local::now().format("%Y%m%d_%H%M%S").to_string());
+        let template = self.notebook.template();
+        if !self.export_template {
+            self.notebook.set_template(None);
+        }
+        let layers = self.export_layers.clone().unwrap_or_else(|| self.visible_layers.clone());
+        let count = self.notebook.pages.len();
+        for format in &self.formats {
+            if *format == Format::Pdf {
+                let path = format!("{}.pdf", stem);
+                let dpi = Device::current().dpi;
+                let pages: Vec<&Document> = self.notebook.pages.iter().collect();
+                if let Err(e) = save_pdf(Path::new(&path), &pages, dpi, &layers) {
+                    eprintln!("Can't save {}: {}", path, e);
+                }
+                continue;
+            }
+            for (index, page) in self.notebook.pages.iter().enumerate() {
+                if count > 1 && page.is_empty() {
+                    continue;
+                }
+                let page_stem = if count > 1 { format!("{}-{:03}", stem, index + 1) } else { stem.clone() };
+                let (path, result) = match *format {
+                    // Rendered offscreen, to leave out the template if needed.
+                    Format::Png => {
+                        let path = format!("{}.png", page_stem);
+                        let (width, height) = self.fb.dims();
+                        let mut display = HeadlessDisplay::new(width, height);
+                        display.color = self.fb.is_color();
+                        page.render_rect(&mut display, &rect!(0, 0, width as i32, height as i32), &layers, self.antialias);
+                        let result = display.save(Path::new(&path));
+                        (path, result)
+                    },
+                    Format::Svg => {
+                        let path = format!("{}.svg", page_stem);
+                        let result = save_svg(Path::new(&path), page, &layers);
+                        (path, result)
+                    },
+                    _ => {
+                        let path = format!("{}.sketch", page_stem);
+                        let model = format!("{:?}", Device::current().model);
+                        let result = save_document(Path::new(&path), page, &model);
+                        (path, result)
+                    },
+                };
+                if let Err(e) = result {
+                    eprintln!("Can't save {}: {}", path, e);
+                }
+            }
+        }
+        self.notebook.set_template(template);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc;
+    use std::{env, fs, process};
+    use headless::HeadlessDisplay;
+    use calibration::Transform;
+    use gesture::GestureEvent;
+    use input::{DeviceEvent, FingerStatus, ButtonStatus, ButtonCode};
+    use display::{Display, Mode};
+    use bindings::{Gesture, Command};
+    use document::Layer;
+    use geom::Dir;
+    use pixmap::Pixmap;
+    use super::Sketch;
+
+    fn sketch() -> Sketch<HeadlessDisplay> {
+        let (_, rx) = mpsc::channel();
+        Sketch::with_events(HeadlessDisplay::new(300, 400), rx, Arc::new(Mutex::new(Transform::identity()))).unwrap()
+    }
+
+    fn finger(status: FingerStatus, id: i32, x: i32, y: i32, time: f64) -> GestureEvent {
+        GestureEvent::Relay(DeviceEvent::Finger {
+            time,
+            id,
+            status,
+            position: pt!(x, y),
+            pressure: 1.0,
+        })
+    }
+
+    // The gesture recognizer relays the fingers before sending the tap.
+    fn tap(sketch: &mut Sketch<HeadlessDisplay>, fingers_count: usize, time: f64) {
+        for id in 0..fingers_count as i32 {
+            sketch.handle(finger(FingerStatus::Down, 10 + id, 200 + 20 * id, 300, time));
+        }
+        for id in 0..fingers_count as i32 {
+            sketch.handle(finger(FingerStatus::Up, 10 + id, 200 + 20 * id, 300, time + 0.05));
+        }
+        sketch.handle(GestureEvent::Tap { center: pt!(220, 300), fingers_count });
+    }
+
+    #[test]
+    fn three_fingers_tap_redoes_undone_stroke() {
+        let mut sketch = sketch();
+        sketch.handle(finger(FingerStatus::Down, 0, 50, 50, 0.0));
+        sketch.handle(finger(FingerStatus::Motion, 0, 100, 50, 0.1));
+        sketch.handle(finger(FingerStatus::Up, 0, 150, 50, 0.2));
+        assert_eq!(sketch.notebook.page().strokes.len(), 1);
+        assert_eq!(sketch.fb.pixel(100, 50), 0x00);
+
+        tap(&mut sketch, 2, 1.0);
+        assert!(sketch.notebook.page().is_empty());
+        assert_eq!(sketch.fb.pixel(100, 50), 0xff);
+        assert_eq!(sketch.fb.pixel(200, 300), 0xff);
+
+        tap(&mut sketch, 3, 2.0);
+        let strokes = &sketch.notebook.page().strokes;
+        assert_eq!(strokes.len(), 1);
+        assert_eq!(strokes[0].points[0].position, pt!(50, 50));
+        assert_eq!(sketch.fb.pixel(100, 50), 0x00);
+        assert_eq!(sketch.fb.pixel(240, 300), 0xff);
+    }
+
+    #[test]
+    fn undo_and_redo_only_refresh_the_stroke() {
+        let mut sketch = sketch();
+        sketch.handle(finger(FingerStatus::Down, 0, 50, 50, 0.0));
+        sketch.handle(finger(FingerStatus::Up, 0, 150, 50, 0.1));
+        let bbox = sketch.notebook.page().strokes[0].bounding_box().unwrap();
+        for &(fingers_count, time) in &[(2, 1.0), (3, 2.0)] {
+            tap(&mut sketch, fingers_count, time);
+            let &(rect, mode) = sketch.fb.updates().last().unwrap();
+            assert_eq!(mode, Mode::Partial);
+            assert!(rect.contains(&bbox.min) && rect.contains(&(bbox.max - 1)));
+            assert!(rect.width() < 300 && rect.height() < 400);
+        }
+        assert_eq!(sketch.fb.pixel(100, 50), 0x00);
+    }
+
+    #[test]
+    fn updates_cover_the_round_caps_of_the_brush() {
+        let mut sketch = sketch();
+        sketch.antialias = false;
+        sketch.pen_width = 9.0;
+        sketch.handle(finger(FingerStatus::Down, 0, 50, 100, 0.0));
+        sketch.handle(finger(FingerStatus::Motion, 0, 100, 100, 0.1));
+        sketch.handle(finger(FingerStatus::Motion, 0, 150, 100, 0.2));
+        sketch.handle(finger(FingerStatus::Up, 0, 150, 100, 0.3));
+        // Black strokes without anti-aliasing are only shown with fast updates.
+        let updates = sketch.fb.updates();
+        assert!(updates.iter().all(|&(_, mode)| mode == Mode::Fast));
+        for &(x, y) in &[(46, 100), (50, 96), (100, 104), (150, 100), (154, 100)] {
+            assert_eq!(sketch.fb.pixel(x, y), 0x00);
+            assert!(updates.iter().any(|&(rect, _)| rect.contains(&pt!(x as i32, y as i32))));
+        }
+        assert_eq!(sketch.fb.pixel(100, 106), 0xff);
+    }
+
+    #[test]
+    fn blank_fills_the_screen() {
+        let mut sketch = sketch();
+        sketch.fb.fill_rect(&rect!(-20, 350, 320, 420), 0x00);
+        assert_eq!(sketch.fb.pixels.iter().filter(|&&gray| gray == 0x00).count(), 300 * 50);
+        sketch.blank();
+        assert!(sketch.fb.pixels.iter().all(|&gray| gray == 0xff));
+        assert_eq!(sketch.fb.updates(), vec![(rect!(0, 0, 300, 400), Mode::Full)]);
+    }
+
+    #[test]
+    fn clear_blanks_the_screen() {
+        let mut sketch = sketch();
+        sketch.handle(finger(FingerStatus::Down, 0, 50, 50, 0.0));
+        sketch.handle(finger(FingerStatus::Up, 0, 150, 50, 0.1));
+        sketch.clear();
+        assert!(sketch.fb.pixels.iter().all(|&gray| gray == 0xff));
+        assert_eq!(sketch.fb.updates().last(), Some(&(rect!(0, 0, 300, 400), Mode::Full)));
+    }
+
+    #[test]
+    fn hiding_the_drawn_layer_switches_layer() {
+        let mut sketch = sketch();
+        sketch.dispatch(Gesture::Tap(4), Command::ToggleLayer(Layer::Ink));
+        assert_eq!(sketch.layer, Layer::Annotation);
+        assert!(!sketch.visible_layers.contains(&Layer::Ink));
+        assert!(sketch.visible_layers.contains(&Layer::Annotation));
+    }
+
+    #[test]
+    fn eraser_swipe_keeps_strokes() {
+        let mut sketch = sketch();
+        sketch.handle(finger(FingerStatus::Down, 0, 50, 100, 0.0));
+        sketch.handle(finger(FingerStatus::Up, 0, 250, 100, 0.1));
+        sketch.erasing = true;
+
+        // Two fingers swiping up across the stroke.
+        for id in 0..2 {
+            sketch.handle(finger(FingerStatus::Down, 10 + id, 100 + 50 * id, 200, 1.0));
+        }
+        for id in 0..2 {
+            sketch.handle(finger(FingerStatus::Motion, 10 + id, 100 + 50 * id, 50, 1.1));
+        }
+        assert!(sketch.notebook.page().is_empty());
+        for id in 0..2 {
+            sketch.handle(finger(FingerStatus::Up, 10 + id, 100 + 50 * id, 20, 1.2));
+        }
+        sketch.handle(GestureEvent::Swipe { dir: Dir::North, start: pt!(125, 200), end: pt!(125, 20), fingers_count: 2 });
+
+        assert!(!sketch.erasing);
+        assert_eq!(sketch.notebook.page().strokes.len(), 1);
+        assert_eq!(sketch.fb.pixel(125, 100), 0x00);
+        assert!(sketch.fb.updates().last().is_some_and(|&(rect, _)| rect.contains(&pt!(125, 100))));
+    }
+
+    fn power(sketch: &mut Sketch<HeadlessDisplay>, time: f64) -> bool {
+        sketch.handle(GestureEvent::Relay(DeviceEvent::Button { time, code: ButtonCode::Power, status: ButtonStatus::Pressed }));
+        sketch.handle(GestureEvent::Relay(DeviceEvent::Button { time: time + 0.1, code: ButtonCode::Power, status: ButtonStatus::Released }))
+    }
+
+    #[test]
+    fn power_button_keeps_running_while_a_page_has_strokes() {
+        let mut sketch = sketch();
+        sketch.formats = Vec::new();
+        sketch.handle(finger(FingerStatus::Down, 0, 50, 50, 0.0));
+        sketch.handle(finger(FingerStatus::Up, 0, 150, 50, 0.1));
+        sketch.notebook.next();
+        assert!(sketch.notebook.page().is_empty());
+        assert!(power(&mut sketch, 1.0));
+        assert!(sketch.notebook.is_empty());
+        assert_eq!(sketch.notebook.pages.len(), 1);
+        assert!(!power(&mut sketch, 2.0));
+    }
+
+    #[test]
+    fn images_with_uppercase_extensions_are_opened() {
+        let path = env::temp_dir().join(format!("sketch-{}.PNG", process::id()));
+        let mut image = Pixmap::new(30, 40);
+        image.set_pixel(15, 20, 0x00);
+        fs::write(&path, image.to_png().unwrap()).unwrap();
+        let mut sketch = sketch();
+        let result = sketch.open(&path);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+        let background = sketch.notebook.page().background.as_ref().unwrap();
+        assert_eq!((background.width, background.height), (300, 400));
+        assert_eq!(background.get_pixel(150, 200), 0x00);
     }
 }
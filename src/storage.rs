@@ -0,0 +1,263 @@
+use std::path::Path;
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::fs::File;
+use document::{Document, Stroke, StrokePoint, Tool, Layer, PressureCurve};
+use geom::Point;
+use pixmap::Pixmap;
+
+// Lossless storage of the strokes of a document.
+// All the numbers are little-endian. The file starts with the magic bytes, followed by:
+// version (u16), width (u32), height (u32), model name length (u16), model name (UTF-8),
+// strokes count (u32) and the strokes. Each stroke is made of:
+// tool (u8: 0 pen, 1 eraser), color (u8), smooth (u8), width (f32), pressure curve min (f32),
+// pressure curve gamma (f32), points count (u32) and the points: x (i32), y (i32), time (f64), pressure (f32).
+// Since version 2, the strokes are followed by the background: present (u8) and, if present,
+// width (u32), height (u32) and the gray levels of the pixels, row by row.
+// Since version 3, the smooth flag of each stroke is followed by its layer (u8: 0 background, 1 ink, 2 annotation).
+pub const MAGIC: &[u8; 4] = b"SKCH";
+pub const VERSION: u16 = 3;
+// Larger dimensions are assumed to come from a corrupt file.
+pub const MAX_SIDE: u32 = 1 << 14;
+
+pub fn save_document<P: AsRef<Path>>(path: P, document: &Document, model: &str) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&document.dims.0.to_le_bytes())?;
+    writer.write_all(&document.dims.1.to_le_bytes())?;
+    writer.write_all(&(model.len() as u16).to_le_bytes())?;
+    writer.write_all(model.as_bytes())?;
+    writer.write_all(&(document.strokes.len() as u32).to_le_bytes())?;
+    for stroke in &document.strokes {
+        let tool: u8 = match stroke.tool {
+            Tool::Pen => 0,
+            Tool::Eraser => 1,
+        };
+        let layer: u8 = match stroke.layer {
+            Layer::Background => 0,
+            Layer::Ink => 1,
+            Layer::Annotation => 2,
+        };
+        writer.write_all(&[tool, stroke.color, stroke.smooth as u8, layer])?;
+        writer.write_all(&stroke.width.to_le_bytes())?;
+        writer.write_all(&stroke.curve.min.to_le_bytes())?;
+        writer.write_all(&stroke.curve.gamma.to_le_bytes())?;
+        writer.write_all(&(stroke.points.len() as u32).to_le_bytes())?;
+        for sp in &stroke.points {
+            writer.write_all(&sp.position.x.to_le_bytes())?;
+            writer.write_all(&sp.position.y.to_le_bytes())?;
+            writer.write_all(&sp.time.to_le_bytes())?;
+            writer.write_all(&sp.pressure.to_le_bytes())?;
+        }
+    }
+    if let Some(ref background) = document.background {
+        writer.write_all(&[1])?;
+        writer.write_all(&background.width.to_le_bytes())?;
+        writer.write_all(&background.height.to_le_bytes())?;
+        writer.write_all(&background.data)?;
+    } else {
+        writer.write_all(&[0])?;
+    }
+    writer.flush()
+}
+
+// Returns the document and the name of the model it was drawn on.
+pub fn load_document<P: AsRef<Path>>(path: P) -> io::Result<(Document, String)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a sketch file"));
+    }
+    let version = u16::from_le_bytes(read_array(&mut reader)?);
+    if version == 0 || version > VERSION {
+        return Err(invalid_data("unsupported version"));
+    }
+    let (width, height) = read_dims(&mut reader)?;
+    let model_len = u16::from_le_bytes(read_array(&mut reader)?) as usize;
+    let mut model = vec![0u8; model_len];
+    reader.read_exact(&mut model)?;
+    let model = String::from_utf8(model).map_err(|_| invalid_data("invalid model name"))?;
+    let mut document = Document::new((width, height));
+    let strokes_count = u32::from_le_bytes(read_array(&mut reader)?);
+    for _ in 0..strokes_count {
+        let [tool, color, smooth] = read_array::<_, [u8; 3]>(&mut reader)?;
+        let tool = match tool {
+            0 => Tool::Pen,
+            1 => Tool::Eraser,
+            _ => return Err(invalid_data("unknown tool")),
+        };
+        let layer = if version >= 3 {
+            match read_array::<_, [u8; 1]>(&mut reader)? {
+                [0] => Layer::Background,
+                [1] => Layer::Ink,
+                [2] => Layer::Annotation,
+                _ => return Err(invalid_data("unknown layer")),
+            }
+        } else {
+            Layer::Ink
+        };
+        let width = f32::from_le_bytes(read_array(&mut reader)?);
+        let curve = PressureCurve {
+            min: f32::from_le_bytes(read_array(&mut reader)?),
+            gamma: f32::from_le_bytes(read_array(&mut reader)?),
+        };
+        let mut stroke = Stroke::new(tool, width, color, curve, smooth != 0);
+        stroke.layer = layer;
+        let points_count = u32::from_le_bytes(read_array(&mut reader)?);
+        for _ in 0..points_count {
+            let x = i32::from_le_bytes(read_array(&mut reader)?);
+            let y = i32::from_le_bytes(read_array(&mut reader)?);
+            stroke.points.push(StrokePoint {
+                position: Point::new(x, y),
+                time: f64::from_le_bytes(read_array(&mut reader)?),
+                pressure: f32::from_le_bytes(read_array(&mut reader)?),
+            });
+        }
+        document.strokes.push(stroke);
+    }
+    if version >= 2 {
+        let [present] = read_array::<_, [u8; 1]>(&mut reader)?;
+        if present != 0 {
+            let (width, height) = read_dims(&mut reader)?;
+            let mut background = Pixmap::new(width, height);
+            reader.read_exact(&mut background.data)?;
+            document.background = Some(background);
+        }
+    }
+    Ok((document, model))
+}
+
+fn read_dims<R: Read>(reader: &mut R) -> io::Result<(u32, u32)> {
+    let width = u32::from_le_bytes(read_array(reader)?);
+    let height = u32::from_le_bytes(read_array(reader)?);
+    if width > MAX_SIDE || height > MAX_SIDE || width.checked_mul(height).is_none() {
+        return Err(invalid_data("invalid dimensions"));
+    }
+    Ok((width, height))
+}
+
+fn read_array<R: Read, A: Default + AsMut<[u8]>>(reader: &mut R) -> io::Result<A> {
+    let mut array = A::default();
+    reader.read_exact(array.as_mut())?;
+    Ok(array)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use std::path::PathBuf;
+    use document::{Document, Stroke, Tool, Layer, PressureCurve};
+    use pixmap::Pixmap;
+    use super::{save_document, load_document, MAGIC};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("sketch-{}-{}.sketch", process::id(), name))
+    }
+
+    // A file of the given version, with one pen stroke of two points.
+    fn old_file(version: u16, background: Option<&Pixmap>) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&300u32.to_le_bytes());
+        data.extend_from_slice(&400u32.to_le_bytes());
+        data.extend_from_slice(&4u16.to_le_bytes());
+        data.extend_from_slice(b"Nova");
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&[0, 0x55, 1]);
+        for value in &[3.0f32, 0.5, 1.0] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&2u32.to_le_bytes());
+        for &(x, y, time, pressure) in &[(10i32, 20i32, 0.5f64, 0.25f32), (30, 40, 0.75, 1.0)] {
+            data.extend_from_slice(&x.to_le_bytes());
+            data.extend_from_slice(&y.to_le_bytes());
+            data.extend_from_slice(&time.to_le_bytes());
+            data.extend_from_slice(&pressure.to_le_bytes());
+        }
+        if version >= 2 {
+            if let Some(background) = background {
+                data.push(1);
+                data.extend_from_slice(&background.width.to_le_bytes());
+                data.extend_from_slice(&background.height.to_le_bytes());
+                data.extend_from_slice(&background.data);
+            } else {
+                data.push(0);
+            }
+        }
+        data
+    }
+
+    fn load(name: &str, data: &[u8]) -> (Document, String) {
+        let path = temp_path(name);
+        fs::write(&path, data).unwrap();
+        let result = load_document(&path);
+        fs::remove_file(&path).unwrap();
+        result.unwrap()
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let mut document = Document::new((300, 400));
+        let mut background = Pixmap::new(300, 400);
+        background.set_pixel(7, 9, 0x33);
+        document.background = Some(background);
+        for &(tool, layer) in &[(Tool::Pen, Layer::Annotation), (Tool::Eraser, Layer::Background)] {
+            let mut stroke = Stroke::new(tool, 2.5, 0xaa, PressureCurve { min: 0.25, gamma: 2.0 }, true);
+            stroke.layer = layer;
+            stroke.push(pt!(10, 20), 0.5, 0.75);
+            stroke.push(pt!(-5, 1000), 1.5, 0.0);
+            document.push(stroke);
+        }
+        let path = temp_path("round-trip");
+        save_document(&path, &document, "Nova").unwrap();
+        let result = load_document(&path);
+        fs::remove_file(&path).unwrap();
+        let (loaded, model) = result.unwrap();
+        assert_eq!(model, "Nova");
+        assert_eq!(loaded.dims, (300, 400));
+        assert_eq!(loaded.background.as_ref().unwrap().get_pixel(7, 9), 0x33);
+        assert_eq!(loaded.strokes.len(), 2);
+        for (a, b) in document.strokes.iter().zip(&loaded.strokes) {
+            assert_eq!((a.tool, a.layer, a.color, a.smooth, a.width), (b.tool, b.layer, b.color, b.smooth, b.width));
+            assert_eq!((a.curve.min, a.curve.gamma), (b.curve.min, b.curve.gamma));
+            let points = |s: &Stroke| -> Vec<_> { s.points.iter().map(|sp| (sp.position, sp.time, sp.pressure)).collect() };
+            assert_eq!(points(a), points(b));
+        }
+    }
+
+    #[test]
+    fn older_versions_are_loaded() {
+        let mut background = Pixmap::new(300, 400);
+        background.set_pixel(7, 9, 0x33);
+        for version in 1..3 {
+            let (document, model) = load(&format!("v{}", version), &old_file(version, Some(&background)));
+            assert_eq!(model, "Nova");
+            assert_eq!(document.dims, (300, 400));
+            // The strokes of the files without layers are on the ink layer.
+            assert_eq!(document.strokes.len(), 1);
+            let stroke = &document.strokes[0];
+            assert_eq!((stroke.tool, stroke.layer, stroke.color, stroke.smooth), (Tool::Pen, Layer::Ink, 0x55, true));
+            assert_eq!(stroke.points[1].position, pt!(30, 40));
+            assert_eq!(stroke.points[0].pressure, 0.25);
+            // The backgrounds appeared in the second version.
+            let pixel = document.background.map(|bg| bg.get_pixel(7, 9));
+            assert_eq!(pixel, if version >= 2 { Some(0x33) } else { None });
+        }
+        let (document, _) = load("v2-blank", &old_file(2, None));
+        assert!(document.background.is_none());
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let path = temp_path("v4");
+        fs::write(&path, old_file(4, None)).unwrap();
+        let result = load_document(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
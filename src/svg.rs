@@ -0,0 +1,107 @@
+use std::path::Path;
+use std::io::{self, Write, BufWriter};
+use std::fs::File;
+use document::{Document, Stroke, Layer, LAYERS, Tool};
+use geom::Rectangle;
+use template::{TEMPLATE_COLOR, DOT_RADIUS};
+
+// Write the strokes of the given layers of a document as SVG paths.
+// The picture is cropped to the region covered by the strokes.
+pub fn save_svg(path: &Path, document: &Document, layers: &[Layer]) -> io::Result<()> {
+    let rect = document.bounding_box().unwrap_or_else(|| {
+        rect!(0, 0, document.dims.0 as i32, document.dims.1 as i32)
+    });
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(file, r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="{}" height="{}" viewBox="{} {} {} {}">"#,
+             rect.width(), rect.height(), rect.min.x, rect.min.y, rect.width(), rect.height())?;
+    writeln!(file, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="white"/>"#,
+             rect.min.x, rect.min.y, rect.width(), rect.height())?;
+    if let (true, Some(background)) = (layers.contains(&Layer::Background), document.background.as_ref()) {
+        writeln!(file, r#"<image x="0" y="0" width="{}" height="{}" xlink:href="data:image/png;base64,{}"/>"#,
+                 background.width, background.height, base64(&background.to_png()?))?;
+    }
+    // The erasers are drawn, in black, in the masks of the strokes drawn before them.
+    if document.strokes.iter().any(|s| s.tool == Tool::Eraser) {
+        writeln!(file, "<defs>")?;
+        for (index, stroke) in document.strokes.iter().enumerate().filter(|&(_, s)| s.tool == Tool::Eraser) {
+            write_stroke(&mut file, stroke, 0x00, &format!(r#" id="e{}""#, index))?;
+        }
+        writeln!(file, "</defs>")?;
+    }
+    let mut masks = 0;
+    for layer in LAYERS.iter().filter(|l| layers.contains(l)) {
+        if let (&Layer::Background, Some(ref template)) = (layer, document.template) {
+            let masked = begin_mask(&mut file, &rect, &document.template_erasers(), &mut masks)?;
+            writeln!(file, r##"<g stroke="#{:02x}{:02x}{:02x}" stroke-linecap="round">"##,
+                     TEMPLATE_COLOR, TEMPLATE_COLOR, TEMPLATE_COLOR)?;
+            for (a, b) in template.lines(document.dims) {
+                writeln!(file, r#"<path d="M{} {} L{} {}" stroke-width="1"/>"#, a.0, a.1, b.0, b.1)?;
+            }
+            for p in template.dots(document.dims) {
+                writeln!(file, r#"<path d="M{} {} L{} {}" stroke-width="{}"/>"#, p.0, p.1, p.0, p.1, 2.0 * DOT_RADIUS)?;
+            }
+            writeln!(file, "</g>")?;
+            if masked {
+                writeln!(file, "</g>")?;
+            }
+        }
+        for (pens, erasers) in document.erased_runs(*layer) {
+            let masked = begin_mask(&mut file, &rect, &erasers, &mut masks)?;
+            for stroke in pens {
+                write_stroke(&mut file, stroke, stroke.color, "")?;
+            }
+            if masked {
+                writeln!(file, "</g>")?;
+            }
+        }
+    }
+    writeln!(file, "</svg>")
+}
+
+fn write_stroke<W: Write>(file: &mut W, stroke: &Stroke, gray: u8, attributes: &str) -> io::Result<()> {
+    writeln!(file, r##"<g{} fill="none" stroke="#{:02x}{:02x}{:02x}" stroke-linecap="round" stroke-linejoin="round">"##,
+             attributes, gray, gray, gray)?;
+    for (radius, points) in stroke.polylines() {
+        let data: Vec<String> = points.iter().enumerate().map(|(i, p)| {
+            format!("{}{:.2} {:.2}", if i == 0 { "M" } else { "L" }, p.0, p.1)
+        }).collect();
+        writeln!(file, r#"<path d="{}" stroke-width="{}"/>"#, data.join(" "), 2.0 * radius)?;
+    }
+    writeln!(file, "</g>")
+}
+
+// Open a group masked by the given erasers, if there are any, and return whether it was opened.
+fn begin_mask<W: Write>(file: &mut W, rect: &Rectangle, erasers: &[usize], count: &mut usize) -> io::Result<bool> {
+    if erasers.is_empty() {
+        return Ok(false);
+    }
+    writeln!(file, r#"<mask id="m{}" maskUnits="userSpaceOnUse" x="{}" y="{}" width="{}" height="{}">"#,
+             count, rect.min.x, rect.min.y, rect.width(), rect.height())?;
+    writeln!(file, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="white"/>"#,
+             rect.min.x, rect.min.y, rect.width(), rect.height())?;
+    for index in erasers {
+        writeln!(file, r##"<use xlink:href="#e{}"/>"##, index)?;
+    }
+    writeln!(file, "</mask>")?;
+    writeln!(file, r#"<g mask="url(#m{})">"#, count)?;
+    *count += 1;
+    Ok(true)
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(4 * (data.len() + 2) / 3);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(value >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
@@ -0,0 +1,125 @@
+// Light gray patterns drawn below the strokes.
+pub const TEMPLATE_COLOR: u8 = 0xc0;
+// Radius, in pixels, of the dots of the dotted template.
+pub const DOT_RADIUS: f32 = 1.5;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TemplateKind {
+    Lined,
+    Grid,
+    Dotted,
+    // Equilateral triangles.
+    Isometric,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Template {
+    pub kind: TemplateKind,
+    // Distance, in pixels, between two consecutive lines or dots.
+    pub spacing: f32,
+    // Whether the paint eraser removes the template.
+    pub erasable: bool,
+}
+
+// Normals of the three families of lines of the isometric template.
+const ISOMETRIC_NORMALS: [(f32, f32); 3] = [(0.0, 1.0), (0.866_025_4, 0.5), (-0.866_025_4, 0.5)];
+
+impl TemplateKind {
+    pub fn parse(name: &str) -> Option<TemplateKind> {
+        match name {
+            "lined" => Some(TemplateKind::Lined),
+            "grid" => Some(TemplateKind::Grid),
+            "dotted" => Some(TemplateKind::Dotted),
+            "isometric" => Some(TemplateKind::Isometric),
+            _ => None,
+        }
+    }
+}
+
+impl Template {
+    // Distance from `value` to the nearest multiple of the spacing.
+    fn offset(&self, value: f32) -> f32 {
+        let rem = value - self.spacing * (value / self.spacing).floor();
+        rem.min(self.spacing - rem)
+    }
+
+    // The gray level of the template at the given pixel, if the pixel belongs to the template.
+    pub fn gray(&self, x: u32, y: u32) -> Option<u8> {
+        let (x, y) = (x as f32, y as f32);
+        let covered = match self.kind {
+            TemplateKind::Lined => self.offset(y) < 0.5,
+            TemplateKind::Grid => self.offset(x) < 0.5 || self.offset(y) < 0.5,
+            TemplateKind::Dotted => {
+                let (dx, dy) = (self.offset(x), self.offset(y));
+                dx * dx + dy * dy <= DOT_RADIUS * DOT_RADIUS
+            },
+            TemplateKind::Isometric => {
+                ISOMETRIC_NORMALS.iter().any(|n| self.offset(n.0 * x + n.1 * y) < 0.5)
+            },
+        };
+        if covered {
+            Some(TEMPLATE_COLOR)
+        } else {
+            None
+        }
+    }
+
+    // The lines of the template, as pairs of end points, for the given dimensions.
+    // Some lines might extend beyond the page.
+    pub fn lines(&self, dims: (u32, u32)) -> Vec<((f32, f32), (f32, f32))> {
+        let (width, height) = (dims.0 as f32, dims.1 as f32);
+        let mut lines = Vec::new();
+        match self.kind {
+            TemplateKind::Lined | TemplateKind::Grid => {
+                let mut y = 0.0;
+                while y <= height {
+                    lines.push(((0.0, y), (width, y)));
+                    y += self.spacing;
+                }
+                if self.kind == TemplateKind::Grid {
+                    let mut x = 0.0;
+                    while x <= width {
+                        lines.push(((x, 0.0), (x, height)));
+                        x += self.spacing;
+                    }
+                }
+            },
+            TemplateKind::Isometric => {
+                let length = width + height;
+                let corners = [(0.0, 0.0), (width, 0.0), (0.0, height), (width, height)];
+                for n in &ISOMETRIC_NORMALS {
+                    let projections: Vec<f32> = corners.iter().map(|c| n.0 * c.0 + n.1 * c.1).collect();
+                    let min = projections.iter().cloned().fold(0.0f32, f32::min);
+                    let max = projections.iter().cloned().fold(0.0f32, f32::max);
+                    let mut k = (min / self.spacing).ceil();
+                    while k * self.spacing <= max {
+                        let c = k * self.spacing;
+                        let (px, py) = (n.0 * c, n.1 * c);
+                        let (dx, dy) = (-n.1 * length, n.0 * length);
+                        lines.push(((px - dx, py - dy), (px + dx, py + dy)));
+                        k += 1.0;
+                    }
+                }
+            },
+            TemplateKind::Dotted => (),
+        }
+        lines
+    }
+
+    // The centers of the dots of the template, for the given dimensions.
+    pub fn dots(&self, dims: (u32, u32)) -> Vec<(f32, f32)> {
+        let mut dots = Vec::new();
+        if self.kind == TemplateKind::Dotted {
+            let mut y = 0.0;
+            while y <= dims.1 as f32 {
+                let mut x = 0.0;
+                while x <= dims.0 as f32 {
+                    dots.push((x, y));
+                    x += self.spacing;
+                }
+                y += self.spacing;
+            }
+        }
+        dots
+    }
+}